@@ -0,0 +1,51 @@
+/*!
+ * Times the interpreter on call-heavy programs.
+ *
+ *     cargo bench --bench dispatch
+ *
+ * Set `RVMI_BASELINE` to the path of another `rvmi` build, e.g. one
+ * built from an older revision, to compare against it.
+ */
+
+#![allow(clippy::needless_return)]
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+const PROGRAMS : [&str; 1] = ["fib.ri"];
+const RUNS : usize = 5;
+
+/**
+ * Best wall-clock time of `RUNS` runs of `rvmi` on `program`.
+ */
+fn time(rvmi : &str, program : &Path) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let out = Command::new(rvmi).arg(program).output().unwrap();
+        let elapsed = start.elapsed();
+        assert!(out.status.success(), "{} {} failed", rvmi, program.display());
+        best = best.min(elapsed);
+    }
+    return best;
+}
+
+fn main() {
+    let rvmi = env!("CARGO_BIN_EXE_rvmi");
+    let baseline = env::var("RVMI_BASELINE").ok();
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+    for name in PROGRAMS.iter() {
+        let program = dir.join(name);
+        let t = time(rvmi, &program);
+        match &baseline {
+            Some(old) => {
+                let t_old = time(old, &program);
+                println!("{:<12} {:>10.2?} baseline {:>10.2?} speedup {:.1}x",
+                    name, t, t_old, t_old.as_secs_f64() / t.as_secs_f64());
+            }
+            None => println!("{:<12} {:>10.2?}", name, t),
+        }
+    }
+}
@@ -0,0 +1,664 @@
+/*!
+ * The byte encoding of the RVM language.
+ *
+ * A byte-encoded program starts with the magic bytes `RVMB` and a
+ * little-endian `u16` format version, followed by the same token stream
+ * as the raw encoding. Every token is a single opcode byte followed by
+ * its operands; see the "Byte Encoding" section of spec.md for the
+ * complete table.
+ */
+
+use super::scanner::{Inst, Token, Tokens, SEPS};
+use super::mem_alloc::{Type, MAX_NESTING};
+use super::ir::{self, Program};
+use super::error::ParseError;
+
+use std::fmt;
+
+pub const MAGIC : [u8; 4] = *b"RVMB";
+/**
+ * Format version, bumped whenever the opcodes or their meaning change:
+ *
+ * 1. the first encoding, with every `load` and `store` global
+ * 2. `load` and `store` address the frame of the call, `gload` and
+ *    `gstore` the globals
+ * 3. `ret`
+ * 4. classes, with `new`, `getfield` and `setfield`
+ * 5. inheritance and methods, with `callm`
+ * 6. arrays, with their types and `newarr`, `aload`, `astore` and `alen`
+ */
+pub const VERSION : u16 = 6;
+/// oldest version still read, as the ones after it only added opcodes and types
+/// while version 1 gave `load` and `store` another meaning
+const MIN_VERSION : u16 = 2;
+
+const OP_SRAW : u8 = 0x00;
+const OP_SCLASS : u8 = 0x01;
+const OP_SFN : u8 = 0x02;
+const OP_DEFUN : u8 = 0x10;
+const OP_ENDEF : u8 = 0x11;
+const OP_DEFCL : u8 = 0x12;
+const OP_ENDCL : u8 = 0x13;
+const OP_FIELD : u8 = 0x14;
+const OP_EXTENDS : u8 = 0x15;
+const OP_METHOD : u8 = 0x16;
+const OP_PUSHI : u8 = 0x20;
+const OP_PUSHF : u8 = 0x21;
+const OP_PUSHV : u8 = 0x22;
+const OP_POP : u8 = 0x23;
+const OP_LOAD : u8 = 0x24;
+const OP_STORE : u8 = 0x25;
+const OP_STORES : u8 = 0x26;
+const OP_ALIAS : u8 = 0x27;
+const OP_DUP : u8 = 0x28;
+const OP_GLOAD : u8 = 0x29;
+const OP_GSTORE : u8 = 0x2a;
+const OP_ADD : u8 = 0x30;
+const OP_SUB : u8 = 0x31;
+const OP_MUL : u8 = 0x32;
+const OP_DIV : u8 = 0x33;
+const OP_REM : u8 = 0x34;
+const OP_EQ : u8 = 0x38;
+const OP_NE : u8 = 0x39;
+const OP_LT : u8 = 0x3a;
+const OP_LE : u8 = 0x3b;
+const OP_GT : u8 = 0x3c;
+const OP_GE : u8 = 0x3d;
+const OP_CALL : u8 = 0x40;
+const OP_LABEL : u8 = 0x41;
+const OP_GOTO : u8 = 0x42;
+const OP_BRANCH : u8 = 0x43;
+const OP_RET : u8 = 0x44;
+const OP_NEW : u8 = 0x50;
+const OP_GETFIELD : u8 = 0x51;
+const OP_SETFIELD : u8 = 0x52;
+const OP_CALLM : u8 = 0x53;
+const OP_NEWARR : u8 = 0x54;
+const OP_ALOAD : u8 = 0x55;
+const OP_ASTORE : u8 = 0x56;
+const OP_ALEN : u8 = 0x57;
+
+const TY_INT : u8 = 0;
+const TY_FLOAT : u8 = 1;
+const TY_STRING : u8 = 2;
+const TY_VOID : u8 = 3;
+const TY_CLASS : u8 = 4;
+const TY_ARRAY : u8 = 5;
+
+/**
+ * The version that introduced opcode `op`, 1 for the first ones and for
+ * opcodes that no version knows.
+ */
+fn opcode_version(op : u8) -> u16 {
+    return match op {
+        OP_GLOAD | OP_GSTORE => 2,
+        OP_RET => 3,
+        OP_FIELD | OP_NEW | OP_GETFIELD | OP_SETFIELD => 4,
+        OP_EXTENDS | OP_METHOD | OP_CALLM => 5,
+        OP_NEWARR | OP_ALOAD | OP_ASTORE | OP_ALEN => 6,
+        _ => 1,
+    };
+}
+
+/**
+ * The version that introduced type tag `ty`, as `opcode_version` does.
+ */
+fn type_version(ty : u8) -> u16 {
+    return match ty {
+        TY_ARRAY => 6,
+        _ => 1,
+    };
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEof(usize),
+    UnknownOpcode(usize, u8),
+    UnknownType(usize, u8),
+    /// an opcode introduced after the version of the file
+    NewerOpcode(usize, u8, u16),
+    /// a type tag introduced after the version of the file
+    NewerType(usize, u8, u16),
+    /// an array type nested deeper than `mem_alloc::MAX_NESTING`
+    TooDeep(usize),
+    InvalidUtf8(usize),
+    /// an operand does not fit in its encoded width
+    OutOfRange(&'static str, usize),
+    /// a token has no spelling in the raw encoding
+    NoRawForm(Token),
+    /// the tokens decode but do not form a valid program
+    Parse(Vec<ParseError>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadMagic => write!(f, "not a byte-encoded program (bad magic)"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported byte encoding version {}", v),
+            Error::UnexpectedEof(at) => write!(f, "offset {}: unexpected end of file", at),
+            Error::UnknownOpcode(at, op) => write!(f, "offset {}: unknown opcode 0x{:02x}", at, op),
+            Error::UnknownType(at, ty) => write!(f, "offset {}: unknown type tag {}", at, ty),
+            Error::NewerOpcode(at, op, v) => {
+                write!(f, "offset {}: opcode 0x{:02x} is not in byte encoding version {}", at, op, v)
+            }
+            Error::NewerType(at, ty, v) => {
+                write!(f, "offset {}: type tag {} is not in byte encoding version {}", at, ty, v)
+            }
+            Error::TooDeep(at) => write!(f, "offset {}: arrays nested deeper than {}", at, MAX_NESTING),
+            Error::InvalidUtf8(at) => write!(f, "offset {}: string is not valid UTF-8", at),
+            Error::OutOfRange(what, val) => write!(f, "{} {} does not fit in the byte encoding", what, val),
+            Error::NoRawForm(token) => write!(f, "{:?} cannot be written in the raw encoding", token),
+            Error::Parse(errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "instruction {}: {}", e.line, e.message())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/**
+ * Tells whether `data` starts with the byte encoding magic.
+ */
+pub fn is_bytecode(data : &[u8]) -> bool {
+    return data.starts_with(&MAGIC);
+}
+
+/**
+ * Decodes a byte-encoded program into the same `Program` that
+ * `ir::make_ir` builds from the raw encoding.
+ */
+pub fn read(data : &[u8]) -> Result<Program, Error> {
+    let decoder = Decoder::new(data)?;
+    return ir::make_ir(decoder).map_err(Error::Parse);
+}
+
+/**
+ * Encodes a program into the byte encoding.
+ */
+pub fn write(prog : &Program) -> Result<Vec<u8>, Error> {
+    let mut enc = Encoder{out: Vec::new()};
+    enc.out.extend_from_slice(&MAGIC);
+    enc.out.extend_from_slice(&VERSION.to_le_bytes());
+    for token in program_tokens(prog) {
+        enc.token(&token)?;
+    }
+    return Ok(enc.out);
+}
+
+/**
+ * Prints a program in the canonical raw encoding, one token per line.
+ *
+ * Assembling the output again yields the same bytes as `write`.
+ */
+pub fn disassemble(prog : &Program) -> Result<String, Error> {
+    let mut out = String::new();
+    for token in program_tokens(prog) {
+        if ! has_raw_form(&token) {
+            return Err(Error::NoRawForm(token));
+        }
+        out.push_str(&token.to_string());
+        out.push('\n');
+    }
+    return Ok(out);
+}
+
+/**
+ * Tells whether the scanner reads the printed token back unchanged.
+ */
+fn has_raw_form(token : &Token) -> bool {
+    let is_word = |s : &str| {
+        !s.is_empty() && !s.starts_with('"') && !s.starts_with(';')
+            && !s.contains(&SEPS[..])
+    };
+    // a class named like another type would read back as that type
+    let is_type = |t : &Type| {
+        let text = t.to_string();
+        is_word(&text) && text.parse() == Ok(t.clone())
+    };
+    return match token {
+        Token::Defun(name, par_ts, ret_t) => {
+            is_word(name) && par_ts.iter().chain(Some(ret_t)).all(is_type)
+        }
+        Token::Call(name) | Token::Defcl(name) | Token::New(name) |
+        Token::Extends(name) | Token::Method(name) | Token::Callm(name) => is_word(name),
+        Token::Field(name, t) => is_word(name) && is_type(t),
+        Token::Newarr(t) => is_type(t),
+        Token::Getfield(class, field) | Token::Setfield(class, field) => {
+            is_word(class) && is_word(field)
+        }
+        Token::Stores(_, data) => !data.contains('"'),
+        _ => true,
+    };
+}
+
+/**
+ * Flattens a program back into its section-ordered token stream.
+ */
+pub fn program_tokens(prog : &Program) -> Vec<Token> {
+    let mut tokens = vec![Token::SRaw, Token::SClass];
+    for decl in &prog.class.defs {
+        tokens.push(Token::Defcl(decl.name.clone()));
+        if let Some(parent) = &decl.parent {
+            tokens.push(Token::Extends(parent.clone()));
+        }
+        for (name, t) in &decl.fields {
+            tokens.push(Token::Field(name.clone(), t.clone()));
+        }
+        for method in &decl.methods {
+            tokens.push(Token::Method(method.clone()));
+        }
+        tokens.push(Token::Endcl);
+    }
+    tokens.push(Token::SFn);
+    for def in &prog.func.defs {
+        tokens.push(Token::Defun(def.name.clone(), def.par_ts.clone(), def.ret_t.clone()));
+        tokens.extend(def.exec.tokens.iter().cloned());
+        tokens.push(Token::Endef);
+    }
+    return tokens;
+}
+
+/**
+ * Streams the instructions of a byte-encoded program.
+ *
+ * Decoding is eager so that malformed input is reported before the IR
+ * builder sees any of it. The byte encoding carries no source positions,
+ * so the `row` of each instruction is its 1-based ordinal in the stream.
+ */
+pub struct Decoder {
+    insts  : Vec<Inst>,
+    index  : usize,
+    errors : Vec<ParseError>
+}
+
+impl Decoder {
+    pub fn new(data : &[u8]) -> Result<Decoder, Error> {
+        let mut r = Reader{data, pos: 0, version: VERSION};
+        if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(Error::BadMagic);
+        }
+        r.version = r.u16()?;
+        if ! (MIN_VERSION..=VERSION).contains(&r.version) {
+            return Err(Error::UnsupportedVersion(r.version));
+        }
+        let mut insts = Vec::new();
+        while r.pos < data.len() {
+            let token = r.token()?;
+            insts.push(Inst{token, row: insts.len() + 1, col: 1});
+        }
+        return Ok(Decoder{insts, index: 0, errors: Vec::new()});
+    }
+}
+
+impl Tokens for Decoder {
+    fn peek(&mut self) -> Option<Inst> {
+        return self.insts.get(self.index).cloned();
+    }
+
+    fn next(&mut self) -> Option<Inst> {
+        let val = self.peek();
+        if val.is_some() {
+            self.index += 1;
+        }
+        return val;
+    }
+
+    fn report(&mut self, err : ParseError) {
+        self.errors.push(err);
+    }
+
+    fn take_errors(&mut self) -> Vec<ParseError> {
+        return std::mem::take(&mut self.errors);
+    }
+
+    fn end(&self) -> (usize, usize) {
+        return (self.insts.len() + 1, 1);
+    }
+}
+
+struct Reader<'a> {
+    data    : &'a [u8],
+    pos     : usize,
+    /// the version of the file, which bounds the opcodes and types read
+    version : u16
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n : usize) -> Result<&'a [u8], Error> {
+        if self.data.len() - self.pos < n {
+            return Err(Error::UnexpectedEof(self.data.len()));
+        }
+        let slice = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        return Ok(slice);
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        return Ok(self.bytes(1)?[0]);
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.bytes(2)?;
+        return Ok(u16::from_le_bytes([b[0], b[1]]));
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.bytes(4)?;
+        return Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.u16()? as usize;
+        let at = self.pos;
+        let b = self.bytes(len)?;
+        return String::from_utf8(b.to_vec()).map_err(|_| Error::InvalidUtf8(at));
+    }
+
+    fn ty(&mut self) -> Result<Type, Error> {
+        let start = self.pos;
+        let mut dims = 0;
+        loop {
+            let at = self.pos;
+            let tag = self.u8()?;
+            if type_version(tag) > self.version {
+                return Err(Error::NewerType(at, tag, self.version));
+            }
+            let elem = match tag {
+                TY_INT => Type::TInt,
+                TY_FLOAT => Type::TFloat,
+                TY_STRING => Type::TString,
+                TY_VOID => Type::Void,
+                TY_CLASS => Type::TClass(self.string()?),
+                TY_ARRAY => {
+                    dims += 1;
+                    if dims > MAX_NESTING {
+                        return Err(Error::TooDeep(start));
+                    }
+                    continue;
+                }
+                t => return Err(Error::UnknownType(at, t)),
+            };
+            return Ok(Type::array_of(elem, dims));
+        }
+    }
+
+    fn addr(&mut self) -> Result<usize, Error> {
+        return Ok(self.u16()? as usize);
+    }
+
+    fn label(&mut self) -> Result<usize, Error> {
+        return Ok(self.u32()? as usize);
+    }
+
+    fn token(&mut self) -> Result<Token, Error> {
+        let at = self.pos;
+        let op = self.u8()?;
+        if opcode_version(op) > self.version {
+            return Err(Error::NewerOpcode(at, op, self.version));
+        }
+        let token = match op {
+            OP_SRAW => Token::SRaw,
+            OP_SCLASS => Token::SClass,
+            OP_SFN => Token::SFn,
+            OP_DEFUN => {
+                let name = self.string()?;
+                let pars = self.u8()?;
+                let mut par_types = Vec::new();
+                for _ in 0..pars {
+                    par_types.push(self.ty()?);
+                }
+                Token::Defun(name, par_types, self.ty()?)
+            }
+            OP_ENDEF => Token::Endef,
+            OP_DEFCL => Token::Defcl(self.string()?),
+            OP_FIELD => {
+                let name = self.string()?;
+                Token::Field(name, self.ty()?)
+            }
+            OP_EXTENDS => Token::Extends(self.string()?),
+            OP_METHOD => Token::Method(self.string()?),
+            OP_ENDCL => Token::Endcl,
+            OP_PUSHI => Token::Pushi(self.u32()? as i32),
+            OP_PUSHF => Token::Pushf(f32::from_bits(self.u32()?)),
+            OP_PUSHV => Token::Pushv(self.addr()?),
+            OP_POP => Token::Pop,
+            OP_LOAD => Token::Load(self.addr()?),
+            OP_STORE => Token::Store(self.addr()?),
+            OP_GLOAD => Token::Gload(self.addr()?),
+            OP_GSTORE => Token::Gstore(self.addr()?),
+            OP_STORES => {
+                let heap = self.addr()?;
+                Token::Stores(heap, self.string()?)
+            }
+            OP_ALIAS => {
+                let var = self.addr()?;
+                Token::Alias(var, self.addr()?)
+            }
+            OP_DUP => Token::Dup,
+            OP_ADD => Token::Add,
+            OP_SUB => Token::Sub,
+            OP_MUL => Token::Mul,
+            OP_DIV => Token::Div,
+            OP_REM => Token::Rem,
+            OP_EQ => Token::Eq,
+            OP_NE => Token::Ne,
+            OP_LT => Token::Lt,
+            OP_LE => Token::Le,
+            OP_GT => Token::Gt,
+            OP_GE => Token::Ge,
+            OP_CALL => Token::Call(self.string()?),
+            OP_LABEL => Token::Label(self.label()?),
+            OP_GOTO => Token::Goto(self.label()?),
+            OP_BRANCH => Token::Branch(self.label()?),
+            OP_RET => Token::Ret,
+            OP_NEW => Token::New(self.string()?),
+            OP_CALLM => Token::Callm(self.string()?),
+            OP_NEWARR => Token::Newarr(self.ty()?),
+            OP_ALOAD => Token::Aload,
+            OP_ASTORE => Token::Astore,
+            OP_ALEN => Token::Alen,
+            OP_GETFIELD => {
+                let class = self.string()?;
+                Token::Getfield(class, self.string()?)
+            }
+            OP_SETFIELD => {
+                let class = self.string()?;
+                Token::Setfield(class, self.string()?)
+            }
+            op => return Err(Error::UnknownOpcode(at, op)),
+        };
+        return Ok(token);
+    }
+}
+
+struct Encoder {
+    out : Vec<u8>
+}
+
+impl Encoder {
+    fn u16(&mut self, what : &'static str, val : usize) -> Result<(), Error> {
+        if val > u16::MAX as usize {
+            return Err(Error::OutOfRange(what, val));
+        }
+        self.out.extend_from_slice(&(val as u16).to_le_bytes());
+        return Ok(());
+    }
+
+    fn u32(&mut self, what : &'static str, val : usize) -> Result<(), Error> {
+        if val > u32::MAX as usize {
+            return Err(Error::OutOfRange(what, val));
+        }
+        self.out.extend_from_slice(&(val as u32).to_le_bytes());
+        return Ok(());
+    }
+
+    fn string(&mut self, s : &str) -> Result<(), Error> {
+        self.u16("string length", s.len())?;
+        self.out.extend_from_slice(s.as_bytes());
+        return Ok(());
+    }
+
+    fn ty(&mut self, t : &Type) -> Result<(), Error> {
+        match t {
+            Type::TInt => self.out.push(TY_INT),
+            Type::TFloat => self.out.push(TY_FLOAT),
+            Type::TString => self.out.push(TY_STRING),
+            Type::Void => self.out.push(TY_VOID),
+            Type::TClass(name) => {
+                self.out.push(TY_CLASS);
+                self.string(name)?;
+            }
+            Type::TArray(elem) => {
+                self.out.push(TY_ARRAY);
+                self.ty(elem)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn op(&mut self, op : u8) -> Result<(), Error> {
+        self.out.push(op);
+        return Ok(());
+    }
+
+    fn token(&mut self, token : &Token) -> Result<(), Error> {
+        match token {
+            Token::SRaw => self.op(OP_SRAW),
+            Token::SClass => self.op(OP_SCLASS),
+            Token::SFn => self.op(OP_SFN),
+            Token::Defun(name, par_ts, ret_t) => {
+                self.op(OP_DEFUN)?;
+                self.string(name)?;
+                if par_ts.len() > u8::MAX as usize {
+                    return Err(Error::OutOfRange("parameter count", par_ts.len()));
+                }
+                self.out.push(par_ts.len() as u8);
+                for t in par_ts {
+                    self.ty(t)?;
+                }
+                self.ty(ret_t)
+            }
+            Token::Endef => self.op(OP_ENDEF),
+            Token::Defcl(name) => {
+                self.op(OP_DEFCL)?;
+                self.string(name)
+            }
+            Token::Field(name, t) => {
+                self.op(OP_FIELD)?;
+                self.string(name)?;
+                self.ty(t)
+            }
+            Token::Extends(parent) => {
+                self.op(OP_EXTENDS)?;
+                self.string(parent)
+            }
+            Token::Method(name) => {
+                self.op(OP_METHOD)?;
+                self.string(name)
+            }
+            Token::Endcl => self.op(OP_ENDCL),
+            Token::Pushi(val) => {
+                self.op(OP_PUSHI)?;
+                self.out.extend_from_slice(&val.to_le_bytes());
+                Ok(())
+            }
+            Token::Pushf(val) => {
+                self.op(OP_PUSHF)?;
+                self.out.extend_from_slice(&val.to_bits().to_le_bytes());
+                Ok(())
+            }
+            Token::Pushv(var) => {
+                self.op(OP_PUSHV)?;
+                self.u16("address", *var)
+            }
+            Token::Pop => self.op(OP_POP),
+            Token::Load(var) => {
+                self.op(OP_LOAD)?;
+                self.u16("address", *var)
+            }
+            Token::Store(var) => {
+                self.op(OP_STORE)?;
+                self.u16("address", *var)
+            }
+            Token::Gload(var) => {
+                self.op(OP_GLOAD)?;
+                self.u16("address", *var)
+            }
+            Token::Gstore(var) => {
+                self.op(OP_GSTORE)?;
+                self.u16("address", *var)
+            }
+            Token::Stores(var, data) => {
+                self.op(OP_STORES)?;
+                self.u16("address", *var)?;
+                self.string(data)
+            }
+            Token::Alias(var, heap) => {
+                self.op(OP_ALIAS)?;
+                self.u16("address", *var)?;
+                self.u16("address", *heap)
+            }
+            Token::Dup => self.op(OP_DUP),
+            Token::Add => self.op(OP_ADD),
+            Token::Sub => self.op(OP_SUB),
+            Token::Mul => self.op(OP_MUL),
+            Token::Div => self.op(OP_DIV),
+            Token::Rem => self.op(OP_REM),
+            Token::Eq => self.op(OP_EQ),
+            Token::Ne => self.op(OP_NE),
+            Token::Lt => self.op(OP_LT),
+            Token::Le => self.op(OP_LE),
+            Token::Gt => self.op(OP_GT),
+            Token::Ge => self.op(OP_GE),
+            Token::Call(name) => {
+                self.op(OP_CALL)?;
+                self.string(name)
+            }
+            Token::Ret => self.op(OP_RET),
+            Token::Label(lbl) => {
+                self.op(OP_LABEL)?;
+                self.u32("label", *lbl)
+            }
+            Token::Goto(lbl) => {
+                self.op(OP_GOTO)?;
+                self.u32("label", *lbl)
+            }
+            Token::Branch(lbl) => {
+                self.op(OP_BRANCH)?;
+                self.u32("label", *lbl)
+            }
+            Token::New(class) => {
+                self.op(OP_NEW)?;
+                self.string(class)
+            }
+            Token::Getfield(class, field) => {
+                self.op(OP_GETFIELD)?;
+                self.string(class)?;
+                self.string(field)
+            }
+            Token::Setfield(class, field) => {
+                self.op(OP_SETFIELD)?;
+                self.string(class)?;
+                self.string(field)
+            }
+            Token::Callm(method) => {
+                self.op(OP_CALLM)?;
+                self.string(method)
+            }
+            Token::Newarr(t) => {
+                self.op(OP_NEWARR)?;
+                self.ty(t)
+            }
+            Token::Aload => self.op(OP_ALOAD),
+            Token::Astore => self.op(OP_ASTORE),
+            Token::Alen => self.op(OP_ALEN),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+/*!
+ * Control-flow graphs of functions.
+ *
+ * `Cfg::build` splits the code of a `DeFun` into basic blocks: a block
+ * starts at the first instruction, at every `label` and after every
+ * `goto`, `branch` and `ret`, and control only enters a block at its
+ * first instruction.
+ */
+
+use super::scanner::Token;
+use super::ir::DeFun;
+
+use std::fmt::Write;
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// index of the first instruction
+    pub start : usize,
+    /// index after the last instruction
+    pub end   : usize,
+    pub succs : Vec<usize>,
+    pub preds : Vec<usize>
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    /// blocks in program order, the entry block first
+    pub blocks : Vec<Block>,
+    /// immediate dominator of each block, `None` if it is unreachable.
+    /// The entry block is its own immediate dominator.
+    pub idom   : Vec<Option<usize>>
+}
+
+impl Cfg {
+    pub fn build(def : &DeFun) -> Cfg {
+        let exec = &def.exec;
+        let tokens = &exec.tokens;
+        let mut starts = vec![0];
+        for (pc, token) in tokens.iter().enumerate() {
+            match token {
+                Token::Label(_) => starts.push(pc),
+                Token::Goto(_) | Token::Branch(_) | Token::Ret => starts.push(pc + 1),
+                _ => {}
+            }
+        }
+        starts.retain(|pc| *pc == 0 || *pc < tokens.len());
+        starts.sort_unstable();
+        starts.dedup();
+
+        let mut cfg = Cfg {blocks: Vec::new(), idom: Vec::new()};
+        for (id, start) in starts.iter().enumerate() {
+            let end = starts.get(id + 1).cloned().unwrap_or(tokens.len());
+            cfg.blocks.push(Block {start: *start, end, succs: Vec::new(), preds: Vec::new()});
+        }
+
+        let nblocks = cfg.blocks.len();
+        for id in 0..nblocks {
+            let block = &cfg.blocks[id];
+            let fallthrough = if id + 1 < nblocks { vec![id + 1] } else { Vec::new() };
+            let last = if block.end > block.start { tokens.get(block.end - 1) } else { None };
+            let target = |lbl : &usize| exec.labels.get(lbl).map(|pc| cfg.block_of(*pc));
+            let mut succs : Vec<usize> = match last {
+                Some(Token::Goto(lbl)) => target(lbl).into_iter().collect(),
+                Some(Token::Branch(lbl)) => target(lbl).into_iter().chain(fallthrough).collect(),
+                Some(Token::Ret) => Vec::new(),
+                _ => fallthrough,
+            };
+            succs.dedup();
+            for succ in &succs {
+                cfg.blocks[*succ].preds.push(id);
+            }
+            cfg.blocks[id].succs = succs;
+        }
+        cfg.idom = cfg.dominators();
+        return cfg;
+    }
+
+    /**
+     * The block containing instruction `pc`.
+     */
+    pub fn block_of(&self, pc : usize) -> usize {
+        return match self.blocks.binary_search_by_key(&pc, |b| b.start) {
+            Ok(id) => id,
+            Err(id) => id - 1,
+        };
+    }
+
+    pub fn reachable(&self, id : usize) -> bool {
+        return self.idom[id].is_some();
+    }
+
+    /**
+     * Reachable blocks in reverse postorder from the entry block.
+     */
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut seen = vec![false; self.blocks.len()];
+        // blocks with the index of the next successor to visit
+        let mut path = vec![(0, 0)];
+        seen[0] = true;
+        while let Some((id, next)) = path.pop() {
+            match self.blocks[id].succs.get(next) {
+                Some(succ) => {
+                    path.push((id, next + 1));
+                    if ! seen[*succ] {
+                        seen[*succ] = true;
+                        path.push((*succ, 0));
+                    }
+                }
+                None => order.push(id),
+            }
+        }
+        order.reverse();
+        return order;
+    }
+
+    /**
+     * Immediate dominators by the iterative algorithm of Cooper, Harvey
+     * and Kennedy.
+     */
+    fn dominators(&self) -> Vec<Option<usize>> {
+        let order = self.reverse_postorder();
+        let mut rank = vec![usize::MAX; self.blocks.len()];
+        for (i, id) in order.iter().enumerate() {
+            rank[*id] = i;
+        }
+        let mut idom : Vec<Option<usize>> = vec![None; self.blocks.len()];
+        idom[0] = Some(0);
+
+        let intersect = |idom : &[Option<usize>], mut a : usize, mut b : usize| {
+            while a != b {
+                while rank[a] > rank[b] {
+                    a = idom[a].unwrap();
+                }
+                while rank[b] > rank[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            return a;
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in order.iter().skip(1) {
+                let mut new = None;
+                for pred in &self.blocks[*id].preds {
+                    if idom[*pred].is_none() {
+                        continue;
+                    }
+                    new = match new {
+                        None => Some(*pred),
+                        Some(other) => Some(intersect(&idom, *pred, other)),
+                    };
+                }
+                if new.is_some() && idom[*id] != new {
+                    idom[*id] = new;
+                    changed = true;
+                }
+            }
+        }
+        return idom;
+    }
+
+    /**
+     * Whether every path from the entry block to `b` passes through `a`.
+     */
+    pub fn dominates(&self, a : usize, b : usize) -> bool {
+        if ! self.reachable(b) {
+            return false;
+        }
+        let mut id = b;
+        loop {
+            if id == a {
+                return true;
+            }
+            match self.idom[id] {
+                Some(up) if up != id => id = up,
+                _ => return false,
+            }
+        }
+    }
+
+    /**
+     * The children of `id` in the dominator tree.
+     */
+    pub fn dominated(&self, id : usize) -> Vec<usize> {
+        return (0..self.blocks.len())
+            .filter(|b| *b != id && self.idom[*b] == Some(id))
+            .collect();
+    }
+
+    /**
+     * Writes the graph in Graphviz DOT with the instructions of each
+     * block inside its node, headed by its immediate dominator.
+     * Unreachable blocks are drawn dashed.
+     */
+    pub fn to_dot(&self, def : &DeFun) -> String {
+        let exec = &def.exec;
+        let mut dot = String::new();
+        writeln!(dot, "digraph \"{}\" {{", escape(&def.name)).unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+        for (id, block) in self.blocks.iter().enumerate() {
+            let mut text = match self.idom[id] {
+                Some(d) if d != id => format!("b{} (idom b{})\\l", id, d),
+                _ => format!("b{}\\l", id),
+            };
+            for pc in block.start..block.end {
+                let token = escape(&exec.tokens[pc].to_string());
+                text += &format!("{:>4}: {}\\l", exec.rows[pc], token);
+            }
+            let style = if self.reachable(id) { "" } else { ", style=dashed" };
+            writeln!(dot, "    b{} [label=\"{}\"{}];", id, text, style).unwrap();
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            let last = if block.end > block.start { exec.tokens.get(block.end - 1) } else { None };
+            let taken = match last {
+                Some(Token::Branch(lbl)) => exec.labels.get(lbl).map(|pc| self.block_of(*pc)),
+                _ => None,
+            };
+            for succ in &block.succs {
+                let label = match taken {
+                    Some(to) if to == *succ => " [label=\"true\"]",
+                    Some(_) => " [label=\"false\"]",
+                    None => "",
+                };
+                writeln!(dot, "    b{} -> b{}{};", id, succ, label).unwrap();
+            }
+        }
+        dot += "}\n";
+        return dot;
+    }
+}
+
+fn escape(text : &str) -> String {
+    return text.replace('\\', "\\\\").replace('"', "\\\"");
+}
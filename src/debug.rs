@@ -0,0 +1,374 @@
+/*!
+ * The interactive debugger of `rvmi debug`.
+ *
+ * The program runs unoptimized, one instruction at a time with
+ * `Machine::step`, so the `pc` of a frame is the index of its next
+ * instruction in `DeFun.exec` and `DeFun.exec.rows` gives its line. The
+ * debugger stops before an instruction at a breakpoint, and after one
+ * that changed a heap cell that is watched.
+ */
+
+use super::ir::Program;
+use super::mem_alloc::Atom;
+use super::runtime;
+use super::vm::{Image, Machine};
+use super::error::VmError;
+
+use std::io::{self, Write};
+
+const HELP : &str = "\
+break LOCATION   stop before LOCATION: a function, FUNCTION:LABEL or a line
+watch ADDRESS    stop when the heap cell at ADDRESS changes, or is
+                 allocated if it is beyond the heap
+delete [N]       remove breakpoint or watchpoint N, or all of them
+info             list the breakpoints and watchpoints
+step, s          run one instruction, into calls
+next, n          run one instruction, over calls
+finish           run until the current call returns
+continue, c      run until a breakpoint, a watchpoint or the end
+stack            print the operand stack of the current call
+locals           print the local variables of the current call
+heap [ADDRESS]   print the heap, or one cell of it within the heap
+backtrace, bt    print the active calls, innermost first
+where            print the next instruction
+quit, q          leave the debugger
+An empty line repeats the last command.";
+
+enum Stop {
+    /// an instruction, by function and index, and how it was named
+    At(usize, usize, String),
+    Line(usize),
+    /// a heap address, and its cell as last seen
+    Watch(usize, String)
+}
+
+/**
+ * How far `resume` runs.
+ */
+enum Until {
+    Step,
+    /// until no more than this many calls are active
+    Depth(usize),
+    Stop
+}
+
+/**
+ * A value as the debugger shows it: like `print` does, but with strings
+ * quoted and unset values named.
+ */
+fn show(atom : &Atom, heap : &[Atom]) -> String {
+    return match atom {
+        Atom::Null => "unset".to_string(),
+        Atom::VString(s) => format!("{:?}", s),
+        _ => {
+            let mut text = Vec::new();
+            match runtime::print(atom, heap, &mut text) {
+                Ok(()) => String::from_utf8_lossy(&text).into_owned(),
+                Err(e) => format!("<{}>", e),
+            }
+        }
+    };
+}
+
+/**
+ * The heap cell at `addr`, with the names and values of the fields of an
+ * object and the elements of an array.
+ */
+fn show_cell(image : &Image, heap : &[Atom], addr : usize) -> String {
+    return match heap.get(addr) {
+        Some(Atom::Object(obj)) => {
+            let class = &image.classes[image.class_ids[&obj.class]];
+            let fields : Vec<String> = class.fields.iter().zip(&obj.fields)
+                .map(|((name, _), val)| format!("{} = {}", name, show(val, heap)))
+                .collect();
+            format!("{} {{{}}}", obj.class, fields.join(", "))
+        }
+        Some(Atom::Array(arr)) => format!("{}[] {}", arr.elem, show(&Atom::Ref(addr), heap)),
+        _ => "empty".to_string(),
+    };
+}
+
+pub struct Debugger<'p> {
+    prog    : &'p Program,
+    machine : Machine<'p>,
+    /// numbered from 1, `None` once deleted
+    stops   : Vec<Option<Stop>>,
+    last    : String,
+    /// how the program ended, once it has
+    ended   : Option<String>
+}
+
+impl<'p> Debugger<'p> {
+    /**
+     * Starts `main` of `prog` on `machine`, which runs the unoptimized
+     * image of `prog`, and stops before its first instruction.
+     */
+    pub fn new(prog : &'p Program, mut machine : Machine<'p>) -> Result<Debugger<'p>, VmError> {
+        // `main` exists in a verified program
+        let main = machine.image().find("main").unwrap();
+        machine.start(main)?;
+        return Ok(Debugger {prog, machine, stops: Vec::new(), last: String::new(), ended: None});
+    }
+
+    /**
+     * Runs one command, writing what it prints to `out`. Tells whether to
+     * go on reading commands.
+     */
+    pub fn command(&mut self, line : &str, out : &mut dyn Write) -> io::Result<bool> {
+        let line = match line.trim() {
+            "" => self.last.clone(),
+            line => line.to_string(),
+        };
+        self.last = line.clone();
+        let words : Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["help"] => writeln!(out, "{}", HELP)?,
+            ["quit"] | ["q"] => return Ok(false),
+            ["break", location] | ["b", location] => {
+                match self.location(location) {
+                    Ok(stop) => {
+                        self.stops.push(Some(stop));
+                        writeln!(out, "breakpoint {} at {}", self.stops.len(), location)?;
+                    }
+                    Err(e) => writeln!(out, "{}", e)?,
+                }
+            }
+            ["watch", addr] => {
+                match addr.parse() {
+                    Ok(addr) => {
+                        let seen = show_cell(self.machine.image(), &self.machine.mem.heap, addr);
+                        self.stops.push(Some(Stop::Watch(addr, seen)));
+                        writeln!(out, "watchpoint {} on heap {}", self.stops.len(), addr)?;
+                    }
+                    Err(_) => writeln!(out, "bad heap address {}", addr)?,
+                }
+            }
+            ["delete"] => self.stops.clear(),
+            ["delete", n] => {
+                match n.parse::<usize>() {
+                    Ok(n) if n >= 1 && self.stops.get(n - 1).is_some_and(|s| s.is_some()) => {
+                        self.stops[n - 1] = None;
+                    }
+                    _ => writeln!(out, "no breakpoint or watchpoint {}", n)?,
+                }
+            }
+            ["info"] => self.info(out)?,
+            ["step"] | ["s"] => self.resume(Until::Step, out)?,
+            ["next"] | ["n"] => {
+                let depth = self.machine.mem.frames.len();
+                self.resume(Until::Depth(depth), out)?;
+            }
+            ["finish"] => {
+                let depth = self.machine.mem.frames.len();
+                self.resume(Until::Depth(depth.saturating_sub(1)), out)?;
+            }
+            ["continue"] | ["c"] => self.resume(Until::Stop, out)?,
+            ["stack"] => self.stack(out)?,
+            ["locals"] => self.locals(out)?,
+            ["heap"] => self.heap(out)?,
+            ["heap", addr] => {
+                match addr.parse() {
+                    Ok(addr) if addr < self.machine.mem.heap.len() => {
+                        let cell = show_cell(self.machine.image(), &self.machine.mem.heap, addr);
+                        writeln!(out, "{}: {}", addr, cell)?;
+                    }
+                    _ => writeln!(out, "bad heap address {}", addr)?,
+                }
+            }
+            ["backtrace"] | ["bt"] => self.backtrace(out)?,
+            ["where"] => self.where_(out)?,
+            _ => writeln!(out, "unknown command `{}`, try `help`", line)?,
+        }
+        return Ok(true);
+    }
+
+    /**
+     * Parses the location of a breakpoint.
+     */
+    fn location(&self, text : &str) -> Result<Stop, String> {
+        let defs = &self.prog.func.defs;
+        if let Ok(row) = text.parse() {
+            if ! defs.iter().any(|d| d.exec.rows.contains(&row)) {
+                return Err(format!("no instruction on line {}", row));
+            }
+            return Ok(Stop::Line(row));
+        }
+        let (name, label) = match text.split_once(':') {
+            Some((name, label)) => (name, Some(label)),
+            None => (text, None),
+        };
+        let func = match defs.iter().position(|d| d.name == name) {
+            Some(func) => func,
+            None => return Err(format!("{}() not found", name)),
+        };
+        let pc = match label {
+            None => 0,
+            Some(label) => {
+                let found = label.parse().ok().and_then(|lbl : usize| defs[func].exec.labels.get(&lbl));
+                match found {
+                    Some(pc) => *pc,
+                    None => return Err(format!("{} has no label {}", name, label)),
+                }
+            }
+        };
+        return Ok(Stop::At(func, pc, text.to_string()));
+    }
+
+    /**
+     * The function and the index of the next instruction of the
+     * innermost call.
+     */
+    fn position(&self) -> Option<(usize, usize)> {
+        return self.machine.mem.frames.last().map(|f| (f.func, f.pc));
+    }
+
+    fn describe(&self, func : usize, pc : usize) -> String {
+        let def = &self.prog.func.defs[func];
+        let (text, row) = match def.exec.tokens.get(pc) {
+            Some(token) => (token.to_string(), def.exec.rows[pc]),
+            None => ("endef".to_string(), def.exec.rows.last().cloned().unwrap_or(0)),
+        };
+        return format!("{} (instruction {}, line {}): {}", def.name, pc, row, text);
+    }
+
+    /**
+     * Steps until `until`, a breakpoint or a watchpoint, and reports where
+     * it stopped.
+     */
+    fn resume(&mut self, until : Until, out : &mut dyn Write) -> io::Result<()> {
+        if let Some(how) = &self.ended {
+            return writeln!(out, "the program has ended: {}", how);
+        }
+        loop {
+            if let Err(e) = self.machine.step() {
+                writeln!(out, "{}", e)?;
+                self.ended = Some(e.fault.to_string());
+                return Ok(());
+            }
+            if self.machine.mem.frames.is_empty() {
+                writeln!(out, "program exited normally")?;
+                self.ended = Some("exited normally".to_string());
+                return Ok(());
+            }
+            let reasons = self.check_stops();
+            for reason in &reasons {
+                writeln!(out, "{}", reason)?;
+            }
+            let done = match until {
+                Until::Step => true,
+                Until::Depth(depth) => self.machine.mem.frames.len() <= depth,
+                Until::Stop => false,
+            };
+            if done || ! reasons.is_empty() {
+                return self.where_(out);
+            }
+        }
+    }
+
+    /**
+     * Why to stop after the last step, if at all.
+     */
+    fn check_stops(&mut self) -> Vec<String> {
+        let position = self.position();
+        let prog = self.prog;
+        let image = self.machine.image();
+        let heap = &self.machine.mem.heap;
+        let mut reasons = Vec::new();
+        for (i, stop) in self.stops.iter_mut().enumerate() {
+            match stop {
+                Some(Stop::At(func, pc, _)) if position == Some((*func, *pc)) => {
+                    reasons.push(format!("breakpoint {}", i + 1));
+                }
+                Some(Stop::Line(row)) => {
+                    let here = position.and_then(|(func, pc)| prog.func.defs[func].exec.rows.get(pc));
+                    if here == Some(row) {
+                        reasons.push(format!("breakpoint {}", i + 1));
+                    }
+                }
+                Some(Stop::Watch(addr, seen)) => {
+                    let now = show_cell(image, heap, *addr);
+                    if now != *seen {
+                        reasons.push(format!("watchpoint {}: heap {} changed from {} to {}",
+                            i + 1, addr, seen, now));
+                        *seen = now;
+                    }
+                }
+                _ => {}
+            }
+        }
+        return reasons;
+    }
+
+    fn where_(&self, out : &mut dyn Write) -> io::Result<()> {
+        return match (self.position(), &self.ended) {
+            (_, Some(how)) => writeln!(out, "the program has ended: {}", how),
+            (Some((func, pc)), None) => writeln!(out, "{}", self.describe(func, pc)),
+            (None, None) => writeln!(out, "no active call"),
+        };
+    }
+
+    fn info(&self, out : &mut dyn Write) -> io::Result<()> {
+        for (i, stop) in self.stops.iter().enumerate() {
+            match stop {
+                Some(Stop::At(_, _, text)) => writeln!(out, "{}: breakpoint at {}", i + 1, text)?,
+                Some(Stop::Line(row)) => writeln!(out, "{}: breakpoint at line {}", i + 1, row)?,
+                Some(Stop::Watch(addr, seen)) => {
+                    writeln!(out, "{}: watchpoint on heap {}, {}", i + 1, addr, seen)?
+                }
+                None => {}
+            }
+        }
+        return Ok(());
+    }
+
+    fn stack(&self, out : &mut dyn Write) -> io::Result<()> {
+        let mem = &self.machine.mem;
+        let base = mem.frames.last().map_or(0, |f| f.base);
+        if mem.stack.len() <= base {
+            return writeln!(out, "empty");
+        }
+        // the top last, where it is pushed
+        for (i, val) in mem.stack[base..].iter().enumerate() {
+            writeln!(out, "{}: {}", i, show(val, &mem.heap))?;
+        }
+        return Ok(());
+    }
+
+    fn locals(&self, out : &mut dyn Write) -> io::Result<()> {
+        let mem = &self.machine.mem;
+        let locals = match mem.frames.last() {
+            Some(frame) if ! frame.locals.is_empty() => &frame.locals,
+            _ => return writeln!(out, "none"),
+        };
+        for (i, val) in locals.iter().enumerate() {
+            writeln!(out, "{}: {}", i, show(val, &mem.heap))?;
+        }
+        return Ok(());
+    }
+
+    fn heap(&self, out : &mut dyn Write) -> io::Result<()> {
+        let heap = &self.machine.mem.heap;
+        let mut empty = true;
+        for (addr, cell) in heap.iter().enumerate() {
+            if ! matches!(cell, Atom::Null) {
+                writeln!(out, "{}: {}", addr, show_cell(self.machine.image(), heap, addr))?;
+                empty = false;
+            }
+        }
+        if empty {
+            writeln!(out, "empty")?;
+        }
+        return Ok(());
+    }
+
+    fn backtrace(&self, out : &mut dyn Write) -> io::Result<()> {
+        // the callers wait after their `call`, and after a fault the
+        // innermost call is past the instruction that faulted
+        for (depth, frame) in self.machine.mem.frames.iter().rev().enumerate() {
+            let pc = if depth == 0 && self.ended.is_none() { frame.pc } else { frame.pc.saturating_sub(1) };
+            writeln!(out, "#{} {}", depth, self.describe(frame.func, pc))?;
+        }
+        return Ok(());
+    }
+}
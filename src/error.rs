@@ -0,0 +1,267 @@
+/*!
+ * Diagnostics reported to the user instead of aborting the host process.
+ */
+
+use super::mem_alloc::{Atom, Type};
+use super::bytecode;
+
+use std::fmt;
+
+/**
+ * A malformed program, found while scanning or while building the IR.
+ *
+ * `line` and `column` are 1-based. For the byte encoding `line` is the
+ * ordinal of the offending instruction.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line     : usize,
+    pub column   : usize,
+    pub found    : String,
+    pub expected : String
+}
+
+impl ParseError {
+    pub fn new(line : usize, column : usize, found : &str, expected : &str) -> ParseError {
+        return ParseError {
+            line, column,
+            found: found.to_string(),
+            expected: expected.to_string()
+        };
+    }
+
+    /**
+     * The message without its position.
+     */
+    pub fn message(&self) -> String {
+        if self.found.is_empty() {
+            return format!("expected {}, found end of file", self.expected);
+        }
+        return format!("expected {}, found `{}`", self.expected, self.found);
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message())
+    }
+}
+
+/**
+ * A problem found by `ir::verify` or `typeck::check` before the program
+ * runs. `row` is `None` for problems with a function as a whole.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub function : String,
+    pub row      : Option<usize>,
+    pub message  : String
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self.row {
+            Some(row) => write!(f, "line {}: in {}: {}", row, self.function, self.message),
+            None => write!(f, "in {}: {}", self.function, self.message),
+        }
+    }
+}
+
+/**
+ * Why a program could not be loaded into a `Vm`.
+ */
+#[derive(Debug, Clone)]
+pub enum LoadError {
+    /// the data is in neither encoding
+    Encoding,
+    Parse(Vec<ParseError>),
+    Bytecode(bytecode::Error),
+    Verify(Vec<VerifyError>),
+    Link(VmError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let lines : Vec<String> = match self {
+            LoadError::Encoding => vec!["neither a raw- nor a byte-encoded program".to_string()],
+            LoadError::Parse(errors) => errors.iter().map(|e| e.to_string()).collect(),
+            LoadError::Bytecode(e) => vec![e.to_string()],
+            LoadError::Verify(errors) => errors.iter().map(|e| e.to_string()).collect(),
+            LoadError::Link(e) => vec![e.to_string()],
+        };
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/**
+ * What went wrong while executing a program.
+ */
+#[derive(Debug, Clone)]
+pub enum Fault {
+    StackUnderflow,
+    LabelNotFound(usize),
+    UnknownFunction(String),
+    /// an operator applied to operands of different types
+    TypeMismatch(&'static str, Atom, Atom),
+    /// an operator applied to a type it is not defined for
+    NotImplemented(&'static str, Atom),
+    UnsetVariable(usize),
+    UnsetGlobal(usize),
+    /// a local or global index beyond the slots an address can name
+    AddressOutOfRange(usize),
+    DivisionByZero,
+    BadInput(String),
+    /// a call with a number of arguments other than the callee's parameters
+    ArgumentCount(usize, usize),
+    /// a call whose argument at the index is not of the parameter's type
+    ArgumentType(usize, Type, Atom),
+    /// a function returned a number of values other than its declared one
+    ReturnCount(usize, usize),
+    /// a function returned a value other than its declared type
+    ReturnType(Type, Atom),
+    Unsupported(String),
+    UnknownClass(String),
+    UnknownField(String, String),
+    UnknownMethod(String),
+    /// a method call on an object of the class, which lacks the method
+    NoMethod(String, String),
+    /// a field access on a value that is not a reference to an object
+    NotAnObject(Atom),
+    /// a field access with the class at the index on an object of another one
+    ClassMismatch(String, String),
+    /// a read of a field, named by its class, before it is set
+    UnsetField(String, String),
+    /// a field of the type set to a value of another one
+    FieldType(String, Type, Atom),
+    /// an object of the class made by the host with a number of fields
+    /// other than the class has
+    FieldCount(String, usize, usize),
+    /// an array access on a value that is not a reference to an array
+    NotAnArray(Atom),
+    NegativeLength(i32),
+    /// an index, and the length of the array it is out of
+    IndexOutOfBounds(i32, usize),
+    UnsetElement(usize),
+    /// an element of an array of the type set to a value of another one
+    ElementType(Type, Atom),
+    /// a host function failed, for the reason it gives
+    Host(String),
+    /// a call nested deeper than the limit on active calls
+    StackOverflow(usize),
+    /// an allocation the heap had no room for, with the cells it cannot
+    /// grow beyond
+    OutOfMemory(usize),
+    /// an allocation of the cells within the limit of the heap that the
+    /// system had no memory for
+    AllocationFailed(usize),
+    /// reading or writing the console failed
+    Io(String),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::StackUnderflow => write!(f, "popping empty stack"),
+            Fault::LabelNotFound(lbl) => write!(f, "label {} not found", lbl),
+            Fault::UnknownFunction(name) => write!(f, "{}() not found", name),
+            Fault::TypeMismatch(op, l, r) => {
+                write!(f, "{}: type mismatch, l={:?}, r={:?}", op, l, r)
+            }
+            Fault::NotImplemented(op, v) => write!(f, "{}: not implemented for {:?}", op, v),
+            Fault::UnsetVariable(var) => write!(f, "variable {} is used before it is set", var),
+            Fault::UnsetGlobal(var) => write!(f, "global {} is used before it is set", var),
+            Fault::AddressOutOfRange(var) => write!(f, "address {} is out of range", var),
+            Fault::DivisionByZero => write!(f, "division by zero"),
+            Fault::BadInput(msg) => write!(f, "bad input: {}", msg),
+            Fault::ArgumentCount(expected, found) => {
+                write!(f, "called with {} arguments, expected {}", found, expected)
+            }
+            Fault::ArgumentType(i, t, v) => write!(f, "argument {} is {:?}, expected {}", i, v, t),
+            Fault::ReturnCount(expected, found) => {
+                write!(f, "returned {} values, expected {}", found, expected)
+            }
+            Fault::ReturnType(t, v) => write!(f, "returned {:?}, expected {}", v, t),
+            Fault::Unsupported(inst) => write!(f, "`{}` cannot be executed", inst),
+            Fault::UnknownClass(name) => write!(f, "class {} not found", name),
+            Fault::UnknownField(class, field) => write!(f, "class {} has no field {}", class, field),
+            Fault::UnknownMethod(name) => write!(f, "method {} not found", name),
+            Fault::NoMethod(class, method) => write!(f, "class {} has no method {}", class, method),
+            Fault::NotAnObject(v) => write!(f, "{:?} is not an object", v),
+            Fault::ClassMismatch(expected, found) => {
+                write!(f, "object of class {}, expected {}", found, expected)
+            }
+            Fault::UnsetField(class, field) => {
+                write!(f, "field {}.{} is used before it is set", class, field)
+            }
+            Fault::NotAnArray(v) => write!(f, "{:?} is not an array", v),
+            Fault::NegativeLength(len) => write!(f, "array of negative length {}", len),
+            Fault::IndexOutOfBounds(i, len) => {
+                write!(f, "index {} out of bounds for an array of length {}", i, len)
+            }
+            Fault::UnsetElement(i) => write!(f, "element {} is used before it is set", i),
+            Fault::ElementType(t, v) => write!(f, "element set to {:?}, expected {}", v, t),
+            Fault::FieldType(field, t, v) => write!(f, "field {} set to {:?}, expected {}", field, v, t),
+            Fault::FieldCount(class, expected, found) => {
+                write!(f, "object of class {} with {} fields, expected {}", class, found, expected)
+            }
+            Fault::Host(msg) => write!(f, "{}", msg),
+            Fault::StackOverflow(max) => write!(f, "stack overflow: more than {} active calls", max),
+            Fault::OutOfMemory(max) => write!(f, "out of memory: the heap cannot grow beyond {} cells", max),
+            Fault::AllocationFailed(cells) => write!(f, "out of memory: no room for {} cells", cells),
+            Fault::Io(msg) => write!(f, "i/o error: {}", msg),
+        }
+    }
+}
+
+/**
+ * An active `call` at the time of a fault.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function : String,
+    pub pc       : usize,
+    pub row      : usize
+}
+
+/**
+ * A runtime fault with the VM-level backtrace leading to it, innermost
+ * call first.
+ */
+#[derive(Debug, Clone)]
+pub struct VmError {
+    pub fault : Fault,
+    pub trace : Vec<TraceFrame>
+}
+
+impl VmError {
+    pub fn new(fault : Fault, function : &str, pc : usize, row : usize) -> VmError {
+        let mut err = VmError {fault, trace: Vec::new()};
+        err.trace.push(TraceFrame {function: function.to_string(), pc, row});
+        return err;
+    }
+
+    /**
+     * Records the `call` instruction this error propagated through.
+     */
+    pub fn called_from(mut self, function : &str, pc : usize, row : usize) -> VmError {
+        self.trace.push(TraceFrame {function: function.to_string(), pc, row});
+        return self;
+    }
+}
+
+impl From<Fault> for VmError {
+    fn from(fault : Fault) -> VmError {
+        return VmError {fault, trace: Vec::new()};
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error: {}", self.fault)?;
+        for frame in &self.trace {
+            write!(f, "\n    at {} (instruction {}, line {})",
+                frame.function, frame.pc, frame.row)?;
+        }
+        Ok(())
+    }
+}
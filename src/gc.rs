@@ -0,0 +1,168 @@
+/*!
+ * Mark-and-sweep garbage collection of `Memory.heap`.
+ *
+ * The roots are the operand stack, the locals of every active call and
+ * the globals. Every heap cell that no chain of `Atom::Ref`s from a root
+ * reaches is cleared and put on `Memory.free`, from which `Memory::alloc`
+ * reuses it. Cells never move, so the references that survive stay valid.
+ *
+ * The heap is measured in cells, an array taking one more cell for each
+ * of its elements. A collection is due before an allocation would take
+ * the cells in use above a threshold, which starts at `Gc.initial` and
+ * after each collection becomes `Gc.growth` times the cells that survived
+ * it. It is also due before they would exceed `Gc.max`, and if the
+ * collection does not make room for the allocation below that, the heap
+ * is full.
+ */
+
+use super::mem_alloc::{Atom, Memory};
+use super::error::Fault;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// cells in use that trigger the first collection
+pub const DEFAULT_INITIAL : usize = 1024;
+pub const DEFAULT_GROWTH : f64 = 2.0;
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub collections : u64,
+    /// objects and arrays allocated
+    pub allocated   : u64,
+    /// objects and arrays freed
+    pub freed       : u64,
+    /// most cells in use at once
+    pub peak        : usize,
+    pub time        : Duration
+}
+
+#[derive(Debug, Clone)]
+pub struct Gc {
+    pub initial : usize,
+    pub growth  : f64,
+    /// cells the heap may never exceed, however many are reachable
+    pub max     : usize,
+    /// cells in use above which the next collection is due
+    next        : usize,
+    /// cells in use
+    used        : usize,
+    pub stats   : Stats
+}
+
+impl Gc {
+    pub fn new(initial : usize, growth : f64) -> Gc {
+        return Gc {initial, growth, max: usize::MAX, next: initial, used: 0, stats: Stats::default()};
+    }
+
+    pub fn used(&self) -> usize {
+        return self.used;
+    }
+
+    /**
+     * Whether to collect before allocating `size` cells.
+     */
+    pub fn due(&self, size : usize) -> bool {
+        let after = self.used.saturating_add(size);
+        return after > self.next || after > self.max;
+    }
+
+    /**
+     * Counts `size` cells allocated.
+     */
+    pub fn allocated(&mut self, size : usize) {
+        self.used += size;
+        self.stats.allocated += 1;
+    }
+
+    /**
+     * Makes room for allocating `size` cells after a collection, or
+     * faults if they do not fit below `max`.
+     */
+    pub fn make_room(&mut self, size : usize) -> Result<(), Fault> {
+        let after = self.used.saturating_add(size);
+        if after > self.max {
+            return Err(Fault::OutOfMemory(self.max));
+        }
+        self.next = self.next.max(after);
+        return Ok(());
+    }
+}
+
+impl Default for Gc {
+    fn default() -> Gc {
+        return Gc::new(DEFAULT_INITIAL, DEFAULT_GROWTH);
+    }
+}
+
+/**
+ * The cells `atom` takes on the heap.
+ */
+pub fn size(atom : &Atom) -> usize {
+    return match atom {
+        Atom::Array(arr) => 1 + arr.items.len(),
+        _ => 1,
+    };
+}
+
+/**
+ * The cells an atom refers to directly.
+ */
+fn refs(atom : &Atom) -> Vec<usize> {
+    let children : &[Atom] = match atom {
+        Atom::Ref(r) => return vec![*r],
+        Atom::Object(obj) => &obj.fields,
+        Atom::Array(arr) => &arr.items,
+        _ => return Vec::new(),
+    };
+    return children.iter().filter_map(|a| match a {
+        Atom::Ref(r) => Some(*r),
+        _ => None,
+    }).collect();
+}
+
+/**
+ * Frees every heap cell that the roots of `mem` do not reach.
+ */
+pub fn collect(mem : &mut Memory) {
+    let start = Instant::now();
+    let mut marked = vec![false; mem.heap.len()];
+    let roots = mem.stack.iter()
+        .chain(mem.frames.iter().flat_map(|f| &f.locals))
+        .chain(&mem.globals);
+    let mut work : Vec<usize> = roots.flat_map(refs).collect();
+    while let Some(r) = work.pop() {
+        if r >= marked.len() || marked[r] {
+            continue;
+        }
+        marked[r] = true;
+        work.extend(refs(&mem.heap[r]));
+    }
+
+    let mut free = vec![false; mem.heap.len()];
+    for r in &mem.free {
+        free[*r] = true;
+    }
+    for (r, cell) in mem.heap.iter_mut().enumerate() {
+        if ! marked[r] && ! free[r] {
+            mem.gc.used -= size(cell);
+            *cell = Atom::Null;
+            mem.free.push(r);
+            mem.gc.stats.freed += 1;
+        }
+    }
+
+    let gc = &mut mem.gc;
+    let live = gc.used;
+    gc.next = gc.initial.max((live as f64 * gc.growth) as usize).max(live + 1);
+    gc.stats.collections += 1;
+    gc.stats.time += start.elapsed();
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "gc: {} collections in {:.3} ms, {} allocations, {} freed, at most {} cells in use",
+            self.collections, self.time.as_secs_f64() * 1000.0,
+            self.allocated, self.freed, self.peak)
+    }
+}
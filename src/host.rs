@@ -0,0 +1,229 @@
+/*!
+ * Functions of the host process that programs call like their own.
+ *
+ * A `Host` maps names to a `Signature` and a Rust closure. A `call` of a
+ * name the program does not define resolves to the host function of that
+ * name, is verified and type-checked against its signature like a call of
+ * a `DeFun`, and runs the closure on the arguments popped from the stack.
+ * The arguments are checked against the signature again when the call
+ * executes, and so is the value the closure returns.
+ *
+ * `Host::default()` holds the builtins `print`, `println` and `readint`.
+ */
+
+use super::mem_alloc::{Atom, Memory, Type};
+use super::error::Fault;
+use super::gc::{self, Gc};
+use super::runtime::{self, Console};
+use super::vm::Image;
+
+use std::fmt;
+
+/**
+ * The parameter and return types of a host function. A parameter of
+ * `None` takes a value of any type.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub par_ts : Vec<Option<Type>>,
+    pub ret_t  : Type
+}
+
+impl Signature {
+    pub fn new(par_ts : Vec<Type>, ret_t : Type) -> Signature {
+        return Signature {par_ts: par_ts.into_iter().map(Some).collect(), ret_t};
+    }
+
+    /**
+     * A signature of `nargs` parameters of any type.
+     */
+    pub fn any(nargs : usize, ret_t : Type) -> Signature {
+        return Signature {par_ts: vec![None; nargs], ret_t};
+    }
+
+    /**
+     * Checks that `args` fit the parameters.
+     */
+    pub fn check_args(&self, args : &[Atom]) -> Result<(), Fault> {
+        if args.len() != self.par_ts.len() {
+            return Err(Fault::ArgumentCount(self.par_ts.len(), args.len()));
+        }
+        for (i, (arg, t)) in args.iter().zip(&self.par_ts).enumerate() {
+            match t {
+                Some(t) if ! arg.has_type(t) => return Err(Fault::ArgumentType(i, t.clone(), arg.clone())),
+                _ => {}
+            }
+        }
+        return Ok(());
+    }
+
+    /**
+     * Checks that `val` is what a function of this signature returns:
+     * `Atom::Null` for `NULL`, else a value of the return type.
+     */
+    pub fn check_return(&self, val : &Atom) -> Result<(), Fault> {
+        return match (&self.ret_t, val) {
+            (Type::Void, Atom::Null) => Ok(()),
+            (Type::Void, _) => Err(Fault::ReturnCount(0, 1)),
+            (t, val) if ! val.has_type(t) => Err(Fault::ReturnType(t.clone(), val.clone())),
+            _ => Ok(()),
+        };
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(")?;
+        for (i, t) in self.par_ts.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match t {
+                Some(t) => write!(f, "{}", t)?,
+                None => write!(f, "any")?,
+            }
+        }
+        write!(f, ") {}", self.ret_t)
+    }
+}
+
+/**
+ * What a host function may reach of the machine besides its arguments:
+ * the heap, to follow and allocate the objects and arrays they refer to,
+ * and the console of the machine.
+ */
+pub struct Context<'m> {
+    pub heap : &'m mut Vec<Atom>,
+    pub free : &'m mut Vec<usize>,
+    pub gc   : &'m mut Gc,
+    pub io   : &'m mut Console,
+    /// the classes the objects allocated must be of
+    pub(crate) image : &'m Image
+}
+
+impl Context<'_> {
+    /**
+     * Stores an object or array on the heap and returns a reference to it.
+     * An object must be of a class of the program and have all of its
+     * fields, and the fields and elements that are set must be of their
+     * types, as if the program had set them.
+     */
+    pub fn alloc(&mut self, val : Atom) -> Result<Atom, Fault> {
+        let image = self.image;
+        match &val {
+            Atom::Object(obj) => {
+                let class = match image.class_ids.get(&obj.class) {
+                    Some(c) => &image.classes[*c],
+                    None => return Err(Fault::UnknownClass(obj.class.clone())),
+                };
+                if obj.fields.len() != class.fields.len() {
+                    return Err(Fault::FieldCount(obj.class.clone(), class.fields.len(), obj.fields.len()));
+                }
+                for (v, (name, t)) in obj.fields.iter().zip(&class.fields) {
+                    if ! matches!(v, Atom::Null) && ! image.conforms(self.heap, v, t) {
+                        return Err(Fault::FieldType(name.clone(), t.clone(), v.clone()));
+                    }
+                }
+            }
+            Atom::Array(arr) => {
+                let mut elem = &arr.elem;
+                while let Type::TArray(inner) = elem {
+                    elem = inner;
+                }
+                if let Type::TClass(name) = elem {
+                    if ! image.class_ids.contains_key(name) {
+                        return Err(Fault::UnknownClass(name.clone()));
+                    }
+                }
+                for v in &arr.items {
+                    if ! matches!(v, Atom::Null) && ! image.conforms(self.heap, v, &arr.elem) {
+                        return Err(Fault::ElementType(arr.elem.clone(), v.clone()));
+                    }
+                }
+            }
+            v => return Err(Fault::NotAnObject(v.clone())),
+        }
+        // the heap cannot be collected while the host function runs
+        if self.gc.used().saturating_add(gc::size(&val)) > self.gc.max {
+            return Err(Fault::OutOfMemory(self.gc.max));
+        }
+        return Ok(Atom::Ref(Memory::alloc(self.heap, self.free, self.gc, val)));
+    }
+}
+
+/**
+ * The closure of a host function. It is given the arguments, the first
+ * parameter first, and returns `Atom::Null` if the function returns
+ * `NULL`.
+ */
+pub type Native = dyn Fn(&[Atom], &mut Context) -> Result<Atom, Fault>;
+
+pub struct HostFn {
+    pub name : String,
+    pub sig  : Signature,
+    func     : Box<Native>
+}
+
+impl fmt::Debug for HostFn {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.name, self.sig)
+    }
+}
+
+/**
+ * The host functions that programs may call.
+ */
+#[derive(Debug)]
+pub struct Host {
+    pub funcs : Vec<HostFn>
+}
+
+impl Host {
+    /**
+     * A registry without any function, not even the builtins.
+     */
+    pub fn new() -> Host {
+        return Host {funcs: Vec::new()};
+    }
+
+    /**
+     * Registers `func` as the host function `name`, replacing any
+     * function registered under that name before.
+     */
+    pub fn register<F>(&mut self, name : &str, sig : Signature, func : F)
+            where F : Fn(&[Atom], &mut Context) -> Result<Atom, Fault> + 'static {
+        let host_fn = HostFn {name: name.to_string(), sig, func: Box::new(func)};
+        match self.find(name) {
+            Some(i) => self.funcs[i] = host_fn,
+            None => self.funcs.push(host_fn),
+        }
+    }
+
+    pub fn find(&self, name : &str) -> Option<usize> {
+        return self.funcs.iter().position(|f| f.name == name);
+    }
+
+    pub fn signature(&self, name : &str) -> Option<&Signature> {
+        return self.find(name).map(|i| &self.funcs[i].sig);
+    }
+
+    /**
+     * Runs host function `i` on `args`, checking both them and the value
+     * it returns against its signature.
+     */
+    pub fn call(&self, i : usize, args : &[Atom], ctx : &mut Context) -> Result<Atom, Fault> {
+        let host_fn = &self.funcs[i];
+        host_fn.sig.check_args(args)?;
+        let val = (host_fn.func)(args, ctx)?;
+        host_fn.sig.check_return(&val)?;
+        return Ok(val);
+    }
+}
+
+impl Default for Host {
+    fn default() -> Host {
+        let mut host = Host::new();
+        runtime::register(&mut host);
+        return host;
+    }
+}
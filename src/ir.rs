@@ -0,0 +1,322 @@
+use super::scanner::{Inst, Token, Tokens};
+use super::error::{ParseError, Fault, VmError};
+use super::mem_alloc::{Memory, Type};
+use super::vm::{Image, Machine};
+use super::host::Host;
+
+pub use super::verify::verify;
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct Exec {
+    pub tokens : Vec<Token>,
+    /// source row of each token, for diagnostics
+    pub rows   : Vec<usize>,
+    pub labels : HashMap<usize, usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub class : Class,
+    pub func : Fn,
+}
+
+#[derive(Debug, Clone)]
+pub struct Class {
+    pub defs : Vec<DeCl>,
+}
+
+/**
+ * A class declared by `defcl`, with the fields and methods it adds to
+ * those of its parent in declaration order.
+ *
+ * Method `m` of class `C` is implemented by the function `C.m`, whose
+ * first parameter is the object the method is called on.
+ */
+#[derive(Debug, Clone)]
+pub struct DeCl {
+    pub name    : String,
+    pub parent  : Option<String>,
+    pub fields  : Vec<(String, Type)>,
+    pub methods : Vec<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct DeFun {
+    pub name : String,
+    pub par_ts : Vec<Type>,
+    pub ret_t  : Type,
+    pub exec : Exec
+}
+
+#[derive(Debug, Clone)]
+pub struct Fn {
+    pub defs : Vec<DeFun>,
+}
+
+impl Class {
+    /**
+     * Index of the class called `name`.
+     */
+    pub fn find(&self, name : &str) -> Option<usize> {
+        return self.defs.iter().position(|d| d.name == name);
+    }
+
+    /**
+     * Class `c` followed by its ancestors, nearest first. The chain ends
+     * early at a parent that is not declared or that would repeat.
+     */
+    pub fn ancestors(&self, c : usize) -> Vec<usize> {
+        let mut chain = vec![c];
+        while let Some(parent) = self.defs[*chain.last().unwrap()].parent.as_ref() {
+            match self.find(parent) {
+                Some(p) if ! chain.contains(&p) => chain.push(p),
+                _ => break,
+            }
+        }
+        return chain;
+    }
+
+    /**
+     * The fields of an object of class `c`: the inherited ones first, in
+     * the order their classes declare them.
+     */
+    pub fn fields(&self, c : usize) -> Vec<&(String, Type)> {
+        return self.ancestors(c).iter().rev()
+            .flat_map(|a| &self.defs[*a].fields)
+            .collect();
+    }
+
+    /**
+     * Index of the field an instruction on class `c` refers to, by its
+     * name or else by its index in `fields`.
+     */
+    pub fn field(&self, c : usize, operand : &str) -> Option<usize> {
+        let fields = self.fields(c);
+        if let Some(i) = fields.iter().position(|(name, _)| name == operand) {
+            return Some(i);
+        }
+        return operand.parse().ok().filter(|i| *i < fields.len());
+    }
+
+    /**
+     * The function that runs `method` on an object of class `c`, the one
+     * of the nearest class declaring it.
+     */
+    pub fn method(&self, c : usize, method : &str) -> Option<String> {
+        return self.ancestors(c).iter()
+            .map(|a| &self.defs[*a])
+            .find(|d| d.methods.iter().any(|m| m == method))
+            .map(|d| format!("{}.{}", d.name, method));
+    }
+
+    /**
+     * Whether a value of type `t` may be used where `to` is expected: the
+     * types are the same, or `t` is a class that inherits from `to`.
+     */
+    pub fn conforms(&self, t : &Type, to : &Type) -> bool {
+        if t == to {
+            return true;
+        }
+        return match (t, to) {
+            (Type::TClass(a), Type::TClass(b)) => match (self.find(a), self.find(b)) {
+                (Some(a), Some(b)) => self.ancestors(a).contains(&b),
+                _ => false,
+            },
+            _ => false,
+        };
+    }
+}
+
+impl Program {
+    pub fn find(&self, name : &str) -> Option<&DeFun> {
+        return self.func.defs.iter().find(|d| d.name == name);
+    }
+
+    /**
+     * The functions that implement `method` in the classes declaring it.
+     */
+    pub fn method_defs(&self, method : &str) -> Vec<&DeFun> {
+        return self.class.defs.iter()
+            .filter(|d| d.methods.iter().any(|m| m == method))
+            .filter_map(|d| self.find(&format!("{}.{}", d.name, method)))
+            .collect();
+    }
+
+    /**
+     * Links the program with the builtins and runs its `main`. With `jit`,
+     * functions are compiled to native code after that many calls where
+     * possible.
+     */
+    pub fn simulate(&self, mem : Memory, jit : Option<u32>) -> Result<Memory, VmError> {
+        let host = Host::default();
+        let image = Image::link(self, &host)?;
+        let main = match image.find("main") {
+            Some(main) => main,
+            None => return Err(VmError::from(Fault::UnknownFunction("main".to_string()))),
+        };
+        let mut machine = Machine::new(&image, &host, mem);
+        if let Some(threshold) = jit {
+            machine.enable_jit(threshold);
+        }
+        machine.call(main)?;
+        return Ok(machine.mem);
+    }
+}
+
+/**
+ * Reports that `found` (or the end of input, if `None`) is not `expected`.
+ */
+fn unexpected<S: Tokens>(scan : &mut S, found : Option<Inst>, expected : &str) {
+    let err = match found {
+        Some(v) => ParseError::new(v.row, v.col, &v.token.to_string(), expected),
+        None => {
+            let (line, column) = scan.end();
+            ParseError::new(line, column, "", expected)
+        }
+    };
+    scan.report(err);
+}
+
+pub fn make_decl<S: Tokens>(mut scan: S) -> (Option<DeCl>, S) {
+    let name = match scan.peek() {
+        Some(Inst {token: Token::Defcl(name), ..}) => name,
+        _ => return (None, scan),
+    };
+    scan.next();
+    let mut decl = DeCl{name, parent: None, fields: Vec::new(), methods: Vec::new()};
+    if let Some(Inst {token: Token::Extends(parent), ..}) = scan.peek() {
+        decl.parent = Some(parent);
+        scan.next();
+    }
+    while let Some(v) = scan.peek() {
+        match v.token {
+            Token::Field(field, t) => decl.fields.push((field, t)),
+            Token::Method(method) => decl.methods.push(method),
+            _ => break,
+        }
+        scan.next();
+    }
+    match scan.peek() {
+        Some(Inst {token: Token::Endcl, ..}) => {
+            scan.next();
+        }
+        v => unexpected(&mut scan, v, "`field`, `method` or `endcl`"),
+    }
+    return (Some(decl), scan);
+}
+
+pub fn make_class<S: Tokens>(mut scan: S) -> (Class, S) {
+    let mut defs : Vec<DeCl> = vec![];
+    match scan.peek() {
+        Some(Inst {token: Token::SClass, ..}) => {
+            scan.next();
+        }
+        v => unexpected(&mut scan, v, "`.class`"),
+    }
+    while let Some(v) = scan.peek() {
+        if let Token::SFn | Token::Defun(..) = v.token {
+            break;
+        }
+        let (decl, scan_) = make_decl(scan);
+        scan = scan_;
+        match decl {
+            Some(decl) => {
+                defs.push(decl);
+            }
+            None => {
+                unexpected(&mut scan, Some(v), "`defcl`");
+                scan.next();
+            }
+        }
+    }
+    return (Class{defs}, scan);
+}
+
+pub fn make_execs<S: Tokens>(mut scan: S) -> (Exec, S) {
+    let mut execs = Exec{tokens: vec![], rows: vec![], labels: HashMap::new()};
+    while let Some(v) = scan.peek() {
+        match v.token {
+            Token::Endef | Token::Defun(..) => {
+                break;
+            }
+            Token::Label(lbl) => {
+                execs.labels.insert(lbl, execs.tokens.len());
+                execs.tokens.push(v.token);
+                execs.rows.push(v.row);
+                scan.next();
+            }
+            _ => {
+                execs.tokens.push(v.token);
+                execs.rows.push(v.row);
+                scan.next();
+            }
+        }
+    }
+    return (execs, scan);
+}
+
+pub fn make_defun<S: Tokens>(mut scan: S) ->
+        (Option<DeFun>, S) {
+    match scan.peek() {
+        Some(Inst {token: Token::Defun(name, par_ts, ret_t), ..}) => {
+            scan.next();
+            let (exec, mut scan) = make_execs(scan);
+            match scan.peek() {
+                Some(Inst {token: Token::Endef, ..}) => {
+                    scan.next();
+                }
+                v => unexpected(&mut scan, v, "`endef`"),
+            }
+            return (Some(DeFun{name, par_ts, ret_t, exec}), scan);
+        }
+        _ => {
+            return (None, scan);
+        }
+    }
+}
+
+pub fn make_fn<S: Tokens>(mut scan: S) -> (Fn, S) {
+    let mut defs : Vec<DeFun> = vec![];
+    match scan.peek() {
+        Some(Inst {token: Token::SFn, ..}) => {
+            scan.next();
+        }
+        v => unexpected(&mut scan, v, "`.function`"),
+    }
+    while let Some(v) = scan.peek() {
+        let (defun, scan_) = make_defun(scan);
+        scan = scan_;
+        match defun {
+            Some(defn) => {
+                defs.push(defn);
+            }
+            None => {
+                unexpected(&mut scan, Some(v), "`defun`");
+                scan.next();
+            }
+        }
+    }
+    return (Fn{defs}, scan);
+}
+
+/**
+ * Builds the program from an instruction stream, returning every problem
+ * found in it, sorted by position, if there is any.
+ */
+pub fn make_ir<S: Tokens>(mut scan : S) -> Result<Program, Vec<ParseError>> {
+    match scan.peek() {
+        Some(Inst {token: Token::SRaw, ..}) => {
+            scan.next();
+        }
+        v => unexpected(&mut scan, v, "`.raw`"),
+    }
+    let (class, scan) = make_class(scan);
+    let (func, mut scan) = make_fn(scan);
+    let errors = scan.take_errors();
+    if errors.is_empty() {
+        return Ok(Program{class, func});
+    }
+    return Err(errors);
+}
@@ -0,0 +1,584 @@
+/*!
+ * x86-64 JIT compiler for int-only functions.
+ *
+ * A function can be compiled if it takes and returns only `int`s, uses
+ * nothing but locals, int arithmetic and comparisons, jumps and calls of
+ * other such functions, and never loads a local before storing it. Such
+ * a function has no side effects, so whenever native code gives up --
+ * on a division by zero or when its calls nest too deep -- the call is
+ * simply run again by the interpreter, which reports the fault as usual.
+ * Native code also gives up when its calls would use more of the native
+ * stack than `STACK_BUDGET`, counting the full frame of every call, and
+ * functions whose single frame exceeds it are never compiled.
+ *
+ * The operand stack of compiled code is the native stack, one 64-bit
+ * slot per value of which only the low 32 bits are used. `rbp` points at
+ * the frame of the active function, with its locals below it and its
+ * arguments above the return address. `rbx` points at the `Ctx` of the
+ * call from Rust.
+ */
+
+use super::vm::{Image, Op};
+use super::mem_alloc::Type;
+
+use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi, DynamicLabel, ExecutableBuffer};
+use dynasmrt::x64::Assembler;
+
+/// deepest nesting of calls native code handles before giving up
+const MAX_DEPTH : usize = 10000;
+
+/// bytes of native stack the frames of native code may take in all
+const STACK_BUDGET : i32 = 1 << 20;
+
+/// `Ctx::status` when native code gave up
+const STATUS_DIV_ZERO : u32 = 1;
+const STATUS_TOO_DEEP : u32 = 2;
+
+/**
+ * State shared by the native code of one call from Rust.
+ */
+#[repr(C)]
+struct Ctx {
+    /// native stack pointer to unwind to when giving up
+    saved_rsp : u64,
+    /// bytes of native stack taken by the active frames
+    used      : u64,
+    depth     : i32,
+    max_depth : i32,
+    status    : u32
+}
+
+type Entry = extern "sysv64" fn(*const i32, *mut Ctx) -> i32;
+
+/**
+ * Compiles the functions of an `Image` once they are called often enough.
+ */
+pub struct Jit {
+    /// whether each function can be compiled at all
+    eligible  : Vec<bool>,
+    /// bytes of native stack a call of each function takes
+    sizes     : Vec<i32>,
+    calls     : Vec<u32>,
+    threshold : u32,
+    entries   : Vec<Option<Entry>>,
+    /// keeps the code of `entries` mapped
+    buffers   : Vec<ExecutableBuffer>
+}
+
+/**
+ * Checks that `func` only uses what the compiler supports, with a
+ * consistent stack depth and no local loaded before it is stored. Gives
+ * the bytes of native stack a call of it takes, `None` if unsupported.
+ */
+fn supported(image : &Image, func : usize) -> Option<i32> {
+    let f = &image.funcs[func];
+    if ! f.par_ts.iter().all(|t| *t == Type::TInt) {
+        return None;
+    }
+    if f.ret_t != Type::TInt && f.ret_t != Type::Void {
+        return None;
+    }
+
+    // stack depth and definitely stored locals before each instruction
+    let mut states : Vec<Option<(usize, Vec<bool>)>> = vec![None; f.code.len() + 1];
+    let mut work = vec![(0, f.par_ts.len(), vec![false; f.nlocals])];
+    let mut deepest = f.par_ts.len();
+    while let Some((pc, depth, stored)) = work.pop() {
+        match &mut states[pc] {
+            Some((known, set)) => {
+                if *known != depth {
+                    return None;
+                }
+                let mut changed = false;
+                for (a, b) in set.iter_mut().zip(&stored) {
+                    if *a && ! *b {
+                        *a = false;
+                        changed = true;
+                    }
+                }
+                if ! changed {
+                    continue;
+                }
+            }
+            slot => *slot = Some((depth, stored)),
+        }
+        let (depth, mut stored) = states[pc].clone().unwrap();
+        if pc == f.code.len() {
+            if depth != f.ret_t.arity() {
+                return None;
+            }
+            continue;
+        }
+        let (pops, pushes) = match &f.code[pc] {
+            Op::Pushi(_) => (0, 1),
+            Op::Pop => (1, 0),
+            Op::Load(i) => {
+                if ! stored[*i] {
+                    return None;
+                }
+                (0, 1)
+            }
+            Op::Store(i) => {
+                stored[*i] = true;
+                (1, 0)
+            }
+            Op::Dup => (1, 2),
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Rem |
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => (2, 1),
+            Op::Goto(_) | Op::Nop | Op::Ret => (0, 0),
+            Op::Branch(_) => (1, 0),
+            Op::Call(g) => {
+                let callee = &image.funcs[*g];
+                (callee.par_ts.len(), callee.ret_t.arity())
+            }
+            Op::TailCall(g) => {
+                let callee = &image.funcs[*g];
+                if depth != callee.par_ts.len() {
+                    return None;
+                }
+                (callee.par_ts.len(), callee.ret_t.arity())
+            }
+            _ => return None,
+        };
+        if depth < pops {
+            return None;
+        }
+        let depth = depth - pops + pushes;
+        deepest = deepest.max(depth);
+        match &f.code[pc] {
+            Op::Goto(target) => work.push((*target, depth, stored)),
+            Op::Branch(target) => {
+                work.push((*target, depth, stored.clone()));
+                work.push((pc + 1, depth, stored));
+            }
+            Op::Ret => {
+                if depth != f.ret_t.arity() {
+                    return None;
+                }
+            }
+            _ => work.push((pc + 1, depth, stored)),
+        }
+    }
+    // the return address, the saved `rbp`, the locals and the operands
+    let frame = 8 * (2 + f.nlocals + deepest);
+    if frame > STACK_BUDGET as usize {
+        return None;
+    }
+    return Some(frame as i32);
+}
+
+/**
+ * Functions `func` calls, directly or not, including itself.
+ */
+fn callees(image : &Image, func : usize) -> Vec<usize> {
+    let mut found = vec![func];
+    let mut i = 0;
+    while i < found.len() {
+        for op in &image.funcs[found[i]].code {
+            if let Op::Call(g) | Op::TailCall(g) = op {
+                if ! found.contains(g) {
+                    found.push(*g);
+                }
+            }
+        }
+        i += 1;
+    }
+    return found;
+}
+
+/**
+ * Labels shared by all functions of one compilation.
+ */
+struct Unit {
+    bodies   : Vec<DynamicLabel>,
+    div_zero : DynamicLabel,
+    too_deep : DynamicLabel
+}
+
+fn slot(i : usize) -> i32 {
+    return -8 * (i as i32 + 1);
+}
+
+/**
+ * Emits the body of `func` under the internal calling convention: the
+ * caller pushes the arguments, first one deepest, and pops them after
+ * the call; the result is returned in `eax`.
+ */
+fn emit_body(ops : &mut Assembler, image : &Image, func : usize, unit : &Unit, index : &[usize],
+        sizes : &[i32]) {
+    let f = &image.funcs[func];
+    let cost = sizes[func];
+    let nargs = f.par_ts.len() as i32;
+    let arity = f.ret_t.arity();
+    let frame = 8 * f.nlocals as i32;
+    let labels : Vec<DynamicLabel> = (0..f.code.len()).map(|_| ops.new_dynamic_label()).collect();
+    let epilogue = ops.new_dynamic_label();
+    let div_zero = unit.div_zero;
+    let too_deep = unit.too_deep;
+
+    dynasm!(ops
+        ; .arch x64
+        ; =>unit.bodies[index[func]]
+        ; add QWORD [rbx + 8], cost
+        ; cmp QWORD [rbx + 8], STACK_BUDGET
+        ; jg =>too_deep
+        ; add DWORD [rbx + 16], 1
+        ; mov eax, [rbx + 16]
+        ; cmp eax, [rbx + 20]
+        ; jg =>too_deep
+        ; push rbp
+        ; mov rbp, rsp
+        ; sub rsp, frame
+    );
+    // the operand stack starts with a copy of the arguments
+    for k in 0..nargs {
+        let arg = 16 + 8 * (nargs - 1 - k);
+        dynasm!(ops
+            ; .arch x64
+            ; push QWORD [rbp + arg]
+        );
+    }
+
+    for (pc, op) in f.code.iter().enumerate() {
+        dynasm!(ops
+            ; .arch x64
+            ; =>labels[pc]
+        );
+        match op {
+            Op::Pushi(val) => dynasm!(ops
+                ; .arch x64
+                ; mov eax, *val
+                ; push rax
+            ),
+            Op::Pop => dynasm!(ops
+                ; .arch x64
+                ; add rsp, 8
+            ),
+            Op::Load(i) => dynasm!(ops
+                ; .arch x64
+                ; push QWORD [rbp + slot(*i)]
+            ),
+            Op::Store(i) => dynasm!(ops
+                ; .arch x64
+                ; pop rax
+                ; mov [rbp + slot(*i)], rax
+            ),
+            Op::Dup => dynasm!(ops
+                ; .arch x64
+                ; push QWORD [rsp]
+            ),
+            Op::Add | Op::Sub | Op::Mul => {
+                // the top of the stack is the left operand
+                dynasm!(ops
+                    ; .arch x64
+                    ; pop rax
+                    ; pop rcx
+                );
+                match op {
+                    Op::Add => dynasm!(ops ; .arch x64 ; add eax, ecx),
+                    Op::Sub => dynasm!(ops ; .arch x64 ; sub eax, ecx),
+                    _ => dynasm!(ops ; .arch x64 ; imul eax, ecx),
+                }
+                dynasm!(ops
+                    ; .arch x64
+                    ; push rax
+                );
+            }
+            Op::Div | Op::Rem => {
+                dynasm!(ops
+                    ; .arch x64
+                    ; pop rax
+                    ; pop rcx
+                    ; test ecx, ecx
+                    ; jz =>div_zero
+                    ; cmp ecx, -1
+                    ; jne >divide
+                );
+                // `idiv` traps on i32::MIN / -1, which wraps instead
+                match op {
+                    Op::Div => dynasm!(ops ; .arch x64 ; neg eax),
+                    _ => dynasm!(ops ; .arch x64 ; xor eax, eax),
+                }
+                dynasm!(ops
+                    ; .arch x64
+                    ; jmp >done
+                    ; divide:
+                    ; cdq
+                    ; idiv ecx
+                );
+                if let Op::Rem = op {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; mov eax, edx
+                    );
+                }
+                dynasm!(ops
+                    ; .arch x64
+                    ; done:
+                    ; push rax
+                );
+            }
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                dynasm!(ops
+                    ; .arch x64
+                    ; pop rax
+                    ; pop rcx
+                    ; cmp eax, ecx
+                );
+                match op {
+                    Op::Eq => dynasm!(ops ; .arch x64 ; sete al),
+                    Op::Ne => dynasm!(ops ; .arch x64 ; setne al),
+                    Op::Lt => dynasm!(ops ; .arch x64 ; setl al),
+                    Op::Le => dynasm!(ops ; .arch x64 ; setle al),
+                    Op::Gt => dynasm!(ops ; .arch x64 ; setg al),
+                    _ => dynasm!(ops ; .arch x64 ; setge al),
+                }
+                dynasm!(ops
+                    ; .arch x64
+                    ; movzx eax, al
+                    ; push rax
+                );
+            }
+            Op::Goto(target) => dynasm!(ops
+                ; .arch x64
+                ; jmp =>labels[*target]
+            ),
+            Op::Branch(target) => dynasm!(ops
+                ; .arch x64
+                ; pop rax
+                ; test eax, eax
+                ; jnz =>labels[*target]
+            ),
+            Op::TailCall(g) if image.funcs[*g].par_ts.len() as i32 <= nargs => {
+                // the arguments, all that is on the stack, replace ours and
+                // the callee returns straight to our caller
+                let args = image.funcs[*g].par_ts.len() as i32;
+                for k in 0..args {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; mov rax, [rsp + 8 * k]
+                        ; mov [rbp + 16 + 8 * k], rax
+                    );
+                }
+                dynasm!(ops
+                    ; .arch x64
+                    ; sub DWORD [rbx + 16], 1
+                    ; sub QWORD [rbx + 8], cost
+                    ; mov rsp, rbp
+                    ; pop rbp
+                    ; jmp =>unit.bodies[index[*g]]
+                );
+            }
+            Op::Call(g) | Op::TailCall(g) => {
+                let callee = &image.funcs[*g];
+                let args = 8 * callee.par_ts.len() as i32;
+                dynasm!(ops
+                    ; .arch x64
+                    ; call =>unit.bodies[index[*g]]
+                    ; add rsp, args
+                );
+                if callee.ret_t.arity() == 1 {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; push rax
+                    );
+                }
+            }
+            Op::Ret => {
+                if arity == 1 {
+                    dynasm!(ops
+                        ; .arch x64
+                        ; pop rax
+                    );
+                }
+                dynasm!(ops
+                    ; .arch x64
+                    ; jmp =>epilogue
+                );
+            }
+            _ => dynasm!(ops
+                ; .arch x64
+                ; nop
+            ),
+        }
+    }
+
+    if arity == 1 {
+        dynasm!(ops
+            ; .arch x64
+            ; pop rax
+        );
+    }
+    dynasm!(ops
+        ; .arch x64
+        ; =>epilogue
+        ; sub DWORD [rbx + 16], 1
+        ; sub QWORD [rbx + 8], cost
+        ; mov rsp, rbp
+        ; pop rbp
+        ; ret
+    );
+}
+
+impl Jit {
+    /**
+     * Prepares to compile functions of `image` on their `threshold`th call.
+     */
+    pub fn new(image : &Image, threshold : u32) -> Jit {
+        let n = image.funcs.len();
+        let frames : Vec<Option<i32>> = if cfg!(target_arch = "x86_64") {
+            (0..n).map(|f| supported(image, f)).collect()
+        } else {
+            vec![None; n]
+        };
+        let mut eligible : Vec<bool> = frames.iter().map(Option::is_some).collect();
+        // a function is only compiled with everything it calls
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for f in 0..n {
+                if eligible[f] && callees(image, f).iter().any(|g| ! eligible[*g]) {
+                    eligible[f] = false;
+                    changed = true;
+                }
+            }
+        }
+        return Jit {
+            eligible,
+            sizes: frames.into_iter().map(|f| f.unwrap_or(0)).collect(),
+            calls: vec![0; n],
+            threshold: threshold.max(1),
+            entries: vec![None; n],
+            buffers: Vec::new()
+        };
+    }
+
+    /**
+     * Counts a call of `func`, and runs it natively if it is compiled or
+     * has just become hot, with at most `frames` calls active at once.
+     * `None` means the interpreter has to run it.
+     */
+    pub fn call(&mut self, image : &Image, func : usize, args : &[i32], frames : usize) -> Option<i32> {
+        if ! self.eligible[func] {
+            return None;
+        }
+        if self.entries[func].is_none() {
+            self.calls[func] += 1;
+            if self.calls[func] < self.threshold || ! self.compile(image, func) {
+                return None;
+            }
+        }
+        let entry = self.entries[func].unwrap();
+        let max_depth = frames.min(MAX_DEPTH) as i32;
+        let mut ctx = Ctx {saved_rsp: 0, used: 0, depth: 0, max_depth, status: 0};
+        let val = entry(args.as_ptr(), &mut ctx);
+        if ctx.status == STATUS_TOO_DEEP {
+            // running it again natively from every level of the
+            // interpreted recursion would take quadratic time
+            self.eligible[func] = false;
+            self.entries[func] = None;
+        }
+        if ctx.status != 0 {
+            return None;
+        }
+        return Some(val);
+    }
+
+    /**
+     * The functions that run as native code, by index.
+     */
+    pub fn compiled(&self) -> Vec<usize> {
+        return (0..self.entries.len()).filter(|f| self.entries[*f].is_some()).collect();
+    }
+
+    /**
+     * Compiles `func` with everything it calls into one buffer.
+     */
+    fn compile(&mut self, image : &Image, func : usize) -> bool {
+        let funcs = callees(image, func);
+        let mut index = vec![0; image.funcs.len()];
+        for (i, f) in funcs.iter().enumerate() {
+            index[*f] = i;
+        }
+        let mut ops = match Assembler::new() {
+            Ok(a) => a,
+            Err(_) => {
+                self.eligible[func] = false;
+                return false;
+            }
+        };
+        let unit = Unit {
+            bodies: funcs.iter().map(|_| ops.new_dynamic_label()).collect(),
+            div_zero: ops.new_dynamic_label(),
+            too_deep: ops.new_dynamic_label()
+        };
+        let unwind = ops.new_dynamic_label();
+
+        // giving up restores the stack of the entry stub and returns
+        dynasm!(ops
+            ; .arch x64
+            ; =>unit.div_zero
+            ; mov DWORD [rbx + 24], STATUS_DIV_ZERO as i32
+            ; jmp =>unwind
+            ; =>unit.too_deep
+            ; mov DWORD [rbx + 24], STATUS_TOO_DEEP as i32
+            ; =>unwind
+            ; mov rsp, [rbx]
+            ; pop r12
+            ; pop rbx
+            ; pop rbp
+            ; ret
+        );
+
+        // an entry stub per function, callable from Rust
+        let mut stubs = Vec::new();
+        for f in &funcs {
+            let nargs = image.funcs[*f].par_ts.len() as i32;
+            stubs.push(ops.offset());
+            dynasm!(ops
+                ; .arch x64
+                ; push rbp
+                ; mov rbp, rsp
+                ; push rbx
+                ; push r12
+                ; mov rbx, rsi
+                ; mov [rbx], rsp
+            );
+            for k in 0..nargs {
+                dynasm!(ops
+                    ; .arch x64
+                    ; movsxd rax, DWORD [rdi + 4 * k]
+                    ; push rax
+                );
+            }
+            dynasm!(ops
+                ; .arch x64
+                ; call =>unit.bodies[index[*f]]
+                ; add rsp, 8 * nargs
+                ; pop r12
+                ; pop rbx
+                ; pop rbp
+                ; ret
+            );
+        }
+        for f in &funcs {
+            emit_body(&mut ops, image, *f, &unit, &index, &self.sizes);
+        }
+
+        let buffer = match ops.finalize() {
+            Ok(b) => b,
+            Err(_) => {
+                self.eligible[func] = false;
+                return false;
+            }
+        };
+        for (f, offset) in funcs.iter().zip(stubs) {
+            if self.entries[*f].is_none() {
+                let entry : Entry = unsafe { std::mem::transmute(buffer.ptr(offset)) };
+                self.entries[*f] = Some(entry);
+            }
+        }
+        self.buffers.push(buffer);
+        return true;
+    }
+}
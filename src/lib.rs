@@ -0,0 +1,316 @@
+/*!
+ * rust_vm -- a stack-based IR and the VM that runs it.
+ *
+ * `Vm` embeds the VM in another program: it loads a program in either
+ * encoding, lets the host register functions the program may call, and
+ * calls the functions of the program with `Atom` arguments.
+ *
+ * ```
+ * use rust_vm::{Atom, Signature, Type, Vm};
+ *
+ * let mut vm = Vm::new();
+ * vm.register("twice", Signature::new(vec![Type::TInt], Type::TInt), |args, _| {
+ *     return match args[0] {
+ *         Atom::VInt(i) => Ok(Atom::VInt(2 * i)),
+ *         _ => unreachable!("checked against the signature"),
+ *     };
+ * });
+ * vm.load_text(".raw .class .function defun main 0 NULL endef \
+ *     defun half 1 int int store 0 pushi 2 load 0 div call twice endef").unwrap();
+ * assert!(matches!(vm.call("half", &[Atom::VInt(42)]), Ok(Atom::VInt(42))));
+ * ```
+ *
+ * The modules below are the parts the VM is built from, for tools that
+ * work on programs rather than run them.
+ */
+
+#![allow(clippy::needless_return)]
+
+pub mod scanner;
+pub mod ir;
+pub mod mem_alloc;
+pub mod runtime;
+pub mod bytecode;
+pub mod error;
+pub mod verify;
+pub mod typeck;
+pub mod vm;
+pub mod jit;
+pub mod tac;
+pub mod cfg;
+pub mod opt;
+pub mod gc;
+pub mod host;
+pub mod debug;
+
+pub use error::{Fault, LoadError, VmError};
+pub use host::{Context, Host, Signature};
+pub use mem_alloc::{Atom, Memory, Type};
+pub use runtime::{Buffer, Console};
+
+use ir::Program;
+use opt::{Options, Profile};
+
+use std::io::{Read, Write};
+
+/**
+ * Reads a program from the raw encoding.
+ */
+pub fn parse_text(text : &str) -> Result<Program, LoadError> {
+    let scanner = scanner::Scanner::from_string(text.to_string());
+    return ir::make_ir(scanner).map_err(LoadError::Parse);
+}
+
+/**
+ * Reads a program from either encoding, telling them apart by the magic.
+ */
+pub fn parse_bytes(data : &[u8]) -> Result<Program, LoadError> {
+    if bytecode::is_bytecode(data) {
+        return bytecode::read(data).map_err(LoadError::Bytecode);
+    }
+    return match std::str::from_utf8(data) {
+        Ok(text) => parse_text(text),
+        Err(_) => Err(LoadError::Encoding),
+    };
+}
+
+/**
+ * A loaded program together with the memory it runs on.
+ *
+ * Host functions, options and limits are set before `load`, which
+ * verifies the program against the host functions registered by then.
+ * Globals and heap stay from one `call` to the next, until the next
+ * `load`.
+ */
+pub struct Vm {
+    host       : Host,
+    opts       : Options,
+    jit        : Option<u32>,
+    max_frames : usize,
+    profile    : Option<Profile>,
+    /// functions compiled in any `call` since the last `load`
+    compiled   : Vec<String>,
+    image      : Option<vm::Image>,
+    mem        : Memory,
+    io         : Console
+}
+
+impl Vm {
+    /**
+     * A VM with the builtins as its only host functions, that runs
+     * programs unoptimized and without limits.
+     */
+    pub fn new() -> Vm {
+        return Vm::with_host(Host::default());
+    }
+
+    /**
+     * A VM with the host functions of `host`, which need not include the
+     * builtins.
+     */
+    pub fn with_host(host : Host) -> Vm {
+        return Vm {
+            host,
+            opts: Options::new(opt::DEFAULT_LEVEL),
+            jit: None,
+            max_frames: usize::MAX,
+            profile: None,
+            compiled: Vec::new(),
+            image: None,
+            mem: Memory::new(),
+            io: Console::stdio()
+        };
+    }
+
+    /**
+     * Registers a host function, see `Host::register`.
+     */
+    pub fn register<F>(&mut self, name : &str, sig : Signature, func : F)
+            where F : Fn(&[Atom], &mut Context) -> Result<Atom, Fault> + 'static {
+        self.host.register(name, sig, func);
+    }
+
+    pub fn host(&self) -> &Host {
+        return &self.host;
+    }
+
+    /**
+     * Makes `readint` read from `input` and `print` and `println` write
+     * to `output`, instead of the standard streams. Host functions reach
+     * them through `Context.io`.
+     */
+    pub fn set_io(&mut self, input : Box<dyn Read>, output : Box<dyn Write>) {
+        self.io = Console::new(input, output);
+    }
+
+    /**
+     * How the programs loaded from now on are optimized.
+     */
+    pub fn set_options(&mut self, opts : Options) {
+        self.opts = opts;
+    }
+
+    /**
+     * Runs int-only functions as native code once they have been called
+     * `threshold` times in one `call`.
+     */
+    pub fn enable_jit(&mut self, threshold : u32) {
+        self.jit = Some(threshold);
+    }
+
+    /**
+     * The names of the functions the JIT has compiled to native code in
+     * any `call` since the last `load`, sorted.
+     */
+    pub fn compiled(&self) -> &[String] {
+        return &self.compiled;
+    }
+
+    /**
+     * Counts the calls made from each call site, see `profile`.
+     */
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::default());
+    }
+
+    /**
+     * The calls counted since `enable_profile`, over all `call`s.
+     */
+    pub fn profile(&self) -> Option<&Profile> {
+        return self.profile.as_ref();
+    }
+
+    /**
+     * Faults calls that would make more than `frames` calls active.
+     */
+    pub fn limit_frames(&mut self, frames : usize) {
+        self.max_frames = frames;
+    }
+
+    /**
+     * Faults allocations that would take more than `cells` heap cells
+     * even after a collection. An array takes a cell for each element
+     * besides its own.
+     */
+    pub fn limit_heap(&mut self, cells : usize) {
+        self.mem.gc.max = cells;
+    }
+
+    /**
+     * Sets when the heap is collected, see `gc::Gc`.
+     */
+    pub fn set_gc(&mut self, initial : usize, growth : f64) {
+        let max = self.mem.gc.max;
+        self.mem.gc = gc::Gc::new(initial, growth);
+        self.mem.gc.max = max;
+    }
+
+    /**
+     * The memory of the loaded program, to follow the references that
+     * `call` returns and to read the statistics of the collector.
+     */
+    pub fn memory(&self) -> &Memory {
+        return &self.mem;
+    }
+
+    /**
+     * Verifies, type-checks, optimizes and links `program`, replacing the
+     * one loaded before and clearing the memory.
+     */
+    pub fn load(&mut self, mut program : Program) -> Result<(), LoadError> {
+        verify::verify(&program, &self.host)
+            .and_then(|_| typeck::check(&program, &self.host))
+            .map_err(LoadError::Verify)?;
+        opt::optimize(&mut program, &self.opts);
+        let image = vm::Image::link(&program, &self.host).map_err(LoadError::Link)?;
+        let old = &self.mem.gc;
+        let mut gc = gc::Gc::new(old.initial, old.growth);
+        gc.max = old.max;
+        self.mem = Memory::new();
+        self.mem.gc = gc;
+        self.compiled.clear();
+        self.image = Some(image);
+        return Ok(());
+    }
+
+    /**
+     * Loads a program in the raw encoding.
+     */
+    pub fn load_text(&mut self, text : &str) -> Result<(), LoadError> {
+        return self.load(parse_text(text)?);
+    }
+
+    /**
+     * Loads a program in either encoding.
+     */
+    pub fn load_bytes(&mut self, data : &[u8]) -> Result<(), LoadError> {
+        return self.load(parse_bytes(data)?);
+    }
+
+    /**
+     * Calls function `name` of the loaded program with `args` and runs it
+     * until it returns. Gives back the value it returns, or `Atom::Null`
+     * if it returns `NULL`.
+     *
+     * The host holding a reference does not keep what it refers to from
+     * being collected: an `Atom::Ref` returned is valid until the next
+     * call, which may free and reuse its cells unless it is passed to
+     * that call, reachable from an argument, or stored in a global.
+     */
+    pub fn call(&mut self, name : &str, args : &[Atom]) -> Result<Atom, VmError> {
+        let image = match &self.image {
+            Some(image) => image,
+            None => return Err(VmError::from(Fault::UnknownFunction(name.to_string()))),
+        };
+        let func = match image.find(name) {
+            Some(func) => func,
+            None => return Err(VmError::from(Fault::UnknownFunction(name.to_string()))),
+        };
+        let callee = &image.funcs[func];
+        if args.len() != callee.par_ts.len() {
+            return Err(VmError::from(Fault::ArgumentCount(callee.par_ts.len(), args.len())));
+        }
+
+        let mut mem = std::mem::take(&mut self.mem);
+        mem.stack.extend(args.iter().cloned());
+        let mut machine = vm::Machine::new(image, &self.host, mem);
+        machine.io = std::mem::take(&mut self.io);
+        machine.limit_frames(self.max_frames);
+        if let Some(threshold) = self.jit {
+            machine.enable_jit(threshold);
+        }
+        if self.profile.is_some() {
+            machine.enable_profile();
+        }
+        let result = machine.call(func);
+        if let Some(profile) = &mut self.profile {
+            for (site, count) in machine.profile().calls {
+                *profile.calls.entry(site).or_insert(0) += count;
+            }
+        }
+        for name in machine.compiled() {
+            if ! self.compiled.contains(&name) {
+                self.compiled.push(name);
+            }
+        }
+        self.compiled.sort();
+        self.mem = machine.mem;
+        self.io = machine.io;
+
+        if let Err(e) = result {
+            self.mem.stack.clear();
+            self.mem.frames.clear();
+            return Err(e);
+        }
+        return match callee.ret_t.arity() {
+            0 => Ok(Atom::Null),
+            _ => Ok(self.mem.stack.pop().unwrap()),
+        };
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        return Vm::new();
+    }
+}
@@ -1,44 +1,408 @@
-/**
+/*!
  * rvmi -- The Rust VM Interpreter.
+ *
+ * A command line over the `rust_vm` library.
  */
 
-#[macro_use]
-extern crate text_io;
+#![allow(clippy::needless_return)]
+
+use rust_vm::{bytecode, cfg, debug, gc, host, ir, mem_alloc, opt, tac, typeck, vm, LoadError, Vm};
 
 use std::fs;
 use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
 
-pub mod scanner;
-pub mod ir;
-pub mod mem_alloc;
-pub mod runtime;
+/// calls after which `--jit` compiles a function
+const JIT_THRESHOLD : u32 = 2;
 
 fn print_help() {
     println!("NAME");
     println!("     rvmi -- The Rust VM Interpreter");
     println!();
     println!("SYNOPSIS");
-    println!("     rvmi [file]");
+    println!("     rvmi [-O0|-O1|-O2] [--inline-size=n] [--use-profile=file]");
+    println!("          [--profile=file] [--jit[=calls]] [--jit-stats] [--gc-threshold=cells]");
+    println!("          [--gc-growth=factor] [--gc-stats] [--max-frames=calls]");
+    println!("          [--max-heap=cells] [file]");
+    println!("     rvmi asm [file.ri] [-o file.rbc]");
+    println!("     rvmi disasm [-O0|-O1|-O2] [file.rbc] [-o file.ri]");
+    println!("     rvmi tac [file] [-o file.tac]");
+    println!("     rvmi cfg [file] [--function name] [-o file.dot]");
+    println!("     rvmi debug [file]");
+    println!();
+    println!("DESCRIPTION");
+    println!("     [file] may be in either the raw or the byte encoding.");
+    println!();
+    println!("     asm     assembles a program into the byte encoding");
+    println!("     disasm  prints a program in the canonical raw encoding");
+    println!("     tac     prints a program as three-address code");
+    println!("     cfg     writes the control-flow graph of each function, or only");
+    println!("             of `name`, in Graphviz DOT");
+    println!("     debug   runs a program under an interactive debugger, see its");
+    println!("             `help` command");
+    println!();
+    println!("     -O1     folds constants, threads jumps and removes dead code");
+    println!("     -O2     also inlines functions of up to `n` instructions (default");
+    println!("             {}) and removes code the control-flow graph cannot reach", opt::DEFAULT_INLINE_SIZE);
+    println!("     --profile      writes how often each call site ran to `file`");
+    println!("     --use-profile  inlines only call sites that ran in that profile,");
+    println!("                    hot ones up to four times the size");
+    println!("     --jit   compiles int-only functions to native code once they");
+    println!("             have been called `calls` times (default {})", JIT_THRESHOLD);
+    println!("     --jit-stats  prints the functions compiled to stderr");
+    println!("     --gc-threshold  collects the heap once `cells` are in use (default");
+    println!("                     {}), then once `factor` times the cells that", gc::DEFAULT_INITIAL);
+    println!("                     survived it are (default --gc-growth={})", gc::DEFAULT_GROWTH);
+    println!("     --gc-stats      prints what the collector did to stderr");
+    println!("     --max-frames    fails once more than `calls` calls are active");
+    println!("     --max-heap      fails once more than `cells` heap cells are reachable,");
+    println!("                     an array taking one cell per element besides its own");
 }
 
-fn main() {
-    let args : Vec<String> = env::args().collect();
-    let path = match args.get(1) {
-        Some(t) => t,
-        None => {
-            print_help();
-            return;
+fn fail(msg : String) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+/**
+ * Reports why the program at `path` could not be loaded, and exits.
+ */
+fn load_failed(path : &str, err : LoadError) -> ! {
+    match err {
+        LoadError::Parse(errors) => {
+            for e in &errors {
+                eprintln!("{}:{}", path, e);
+            }
+        }
+        LoadError::Verify(errors) => {
+            for e in &errors {
+                eprintln!("{}: {}", path, e);
+            }
+        }
+        e => eprintln!("{}: {}", path, e),
+    }
+    process::exit(1);
+}
+
+fn read_file(path : &str) -> Vec<u8> {
+    return match fs::read(path) {
+        Ok(d) => d,
+        Err(e) => fail(format!("{}: {}", path, e)),
+    };
+}
+
+/**
+ * Reads a program from either encoding.
+ */
+fn load_program(path : &str) -> ir::Program {
+    return match rust_vm::parse_bytes(&read_file(path)) {
+        Ok(p) => p,
+        Err(e) => load_failed(path, e),
+    };
+}
+
+/**
+ * Splits `[file] [-o output]` arguments of the conversion subcommands.
+ */
+fn io_args(cmd : &str, args : &[String]) -> (String, Option<String>) {
+    let mut input = None;
+    let mut output = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "-o" {
+            match iter.next() {
+                Some(o) => output = Some(o.clone()),
+                None => fail(format!("{}: -o requires a file name", cmd)),
+            }
+        } else if input.is_none() {
+            input = Some(arg.clone());
+        } else {
+            fail(format!("{}: unexpected argument {}", cmd, arg));
         }
+    }
+    return match input {
+        Some(i) => (i, output),
+        None => fail(format!("{}: missing input file", cmd)),
     };
-    let scanner = scanner::Scanner::from_string(fs::read_to_string(path)
-        .unwrap());
-    /*
-    while scanner.has_next() {
-        println!("{:?}", scanner.next());
+}
+
+/**
+ * Verifies and type-checks a program that calls the functions of `host`,
+ * exiting with its errors if any.
+ */
+fn check_program(path : &str, program : &ir::Program, host : &host::Host) {
+    if let Err(errors) = ir::verify(program, host).and_then(|_| typeck::check(program, host)) {
+        for e in &errors {
+            eprintln!("{}: {}", path, e);
+        }
+        process::exit(1);
     }
-    */
+}
 
-    let program = ir::make_ir(scanner);
-    // println!("{:?}", program);
-    program.simulate(Box::new(mem_alloc::Memory::new()));
+/**
+ * The level of an `-O` flag, `None` if `arg` is not one.
+ */
+fn opt_level(arg : &str) -> Option<u8> {
+    let level = arg.strip_prefix("-O")?;
+    return match level.parse() {
+        Ok(n) if n <= opt::MAX_LEVEL => Some(n),
+        _ => fail(format!("{}: optimization level must be 0 to {}", arg, opt::MAX_LEVEL)),
+    };
+}
+
+fn asm(args : &[String]) {
+    let (input, output) = io_args("asm", args);
+    let output = output.unwrap_or_else(|| {
+        Path::new(&input).with_extension("rbc").to_string_lossy().into_owned()
+    });
+    let bytes = match bytecode::write(&load_program(&input)) {
+        Ok(b) => b,
+        Err(e) => fail(format!("{}: {}", input, e)),
+    };
+    if let Err(e) = fs::write(&output, bytes) {
+        fail(format!("{}: {}", output, e));
+    }
+}
+
+fn disasm(args : &[String]) {
+    let mut level = opt::DEFAULT_LEVEL;
+    let mut rest = Vec::new();
+    for arg in args {
+        match opt_level(arg) {
+            Some(n) => level = n,
+            None => rest.push(arg.clone()),
+        }
+    }
+    let (input, output) = io_args("disasm", &rest);
+    let mut program = load_program(&input);
+    if level > 0 {
+        check_program(&input, &program, &host::Host::default());
+        opt::optimize(&mut program, &opt::Options::new(level));
+    }
+    let text = match bytecode::disassemble(&program) {
+        Ok(t) => t,
+        Err(e) => fail(format!("{}: {}", input, e)),
+    };
+    match output {
+        Some(o) => {
+            if let Err(e) = fs::write(&o, text) {
+                fail(format!("{}: {}", o, e));
+            }
+        }
+        None => print!("{}", text),
+    }
+}
+
+fn tac(args : &[String]) {
+    let (input, output) = io_args("tac", args);
+    let program = load_program(&input);
+    let host = host::Host::default();
+    check_program(&input, &program, &host);
+    let mut text = String::new();
+    for func in tac::lower_program(&program, &host) {
+        text += &format!("{}\n", func);
+    }
+    match output {
+        Some(o) => {
+            if let Err(e) = fs::write(&o, text) {
+                fail(format!("{}: {}", o, e));
+            }
+        }
+        None => print!("{}", text),
+    }
+}
+
+fn cfg(args : &[String]) {
+    let mut rest = Vec::new();
+    let mut function = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--function" {
+            match iter.next() {
+                Some(name) => function = Some(name.clone()),
+                None => fail("cfg: --function requires a function name".to_string()),
+            }
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+    let (input, output) = io_args("cfg", &rest);
+    let program = load_program(&input);
+    let defs : Vec<&ir::DeFun> = program.func.defs.iter()
+        .filter(|d| function.as_ref().is_none_or(|name| d.name == *name))
+        .collect();
+    if defs.is_empty() {
+        fail(format!("{}: {}() not found", input, function.unwrap_or_default()));
+    }
+    let mut text = String::new();
+    for def in defs {
+        text += &cfg::Cfg::build(def).to_dot(def);
+    }
+    match output {
+        Some(o) => {
+            if let Err(e) = fs::write(&o, text) {
+                fail(format!("{}: {}", o, e));
+            }
+        }
+        None => print!("{}", text),
+    }
+}
+
+fn run(args : &[String]) {
+    let mut path = None;
+    let mut jit = None;
+    let mut opts = opt::Options::new(opt::DEFAULT_LEVEL);
+    let mut profile_out = None;
+    let mut heap = gc::Gc::default();
+    let mut gc_stats = false;
+    let mut jit_stats = false;
+    let mut max_frames = None;
+    for arg in args {
+        if let Some(n) = opt_level(arg) {
+            opts.level = n;
+        } else if let Some(file) = arg.strip_prefix("--profile=") {
+            profile_out = Some(file.to_string());
+        } else if let Some(file) = arg.strip_prefix("--use-profile=") {
+            let text = match fs::read_to_string(file) {
+                Ok(t) => t,
+                Err(e) => fail(format!("{}: {}", file, e)),
+            };
+            match opt::Profile::parse(&text) {
+                Ok(p) => opts.profile = Some(p),
+                Err(e) => fail(format!("{}:{}", file, e)),
+            }
+        } else if let Some(size) = arg.strip_prefix("--inline-size=") {
+            match size.parse() {
+                Ok(n) => opts.inline_size = n,
+                Err(_) => fail(format!("--inline-size: bad size {}", size)),
+            }
+        } else if arg == "--jit" {
+            jit = Some(JIT_THRESHOLD);
+        } else if let Some(calls) = arg.strip_prefix("--jit=") {
+            match calls.parse() {
+                Ok(n) => jit = Some(n),
+                Err(_) => fail(format!("--jit: bad number of calls {}", calls)),
+            }
+        } else if let Some(cells) = arg.strip_prefix("--gc-threshold=") {
+            match cells.parse() {
+                Ok(n) => heap.initial = n,
+                Err(_) => fail(format!("--gc-threshold: bad number of cells {}", cells)),
+            }
+        } else if let Some(factor) = arg.strip_prefix("--gc-growth=") {
+            match factor.parse() {
+                Ok(f) if f >= 1.0 => heap.growth = f,
+                _ => fail(format!("--gc-growth: bad factor {}, expected at least 1", factor)),
+            }
+        } else if arg == "--gc-stats" {
+            gc_stats = true;
+        } else if arg == "--jit-stats" {
+            jit_stats = true;
+        } else if let Some(calls) = arg.strip_prefix("--max-frames=") {
+            match calls.parse() {
+                Ok(n) => max_frames = Some(n),
+                Err(_) => fail(format!("--max-frames: bad number of calls {}", calls)),
+            }
+        } else if let Some(cells) = arg.strip_prefix("--max-heap=") {
+            match cells.parse() {
+                Ok(n) => heap.max = n,
+                Err(_) => fail(format!("--max-heap: bad number of cells {}", cells)),
+            }
+        } else if path.is_none() {
+            path = Some(arg.as_str());
+        } else {
+            fail(format!("unexpected argument {}", arg));
+        }
+    }
+    let path = match path {
+        Some(p) => p,
+        None => fail("missing input file".to_string()),
+    };
+    if jit.is_some() && profile_out.is_some() {
+        fail("--profile cannot count the calls of native code, drop --jit".to_string());
+    }
+
+    let mut vm = Vm::new();
+    vm.set_options(opts);
+    vm.set_gc(heap.initial, heap.growth);
+    vm.limit_heap(heap.max);
+    if let Some(frames) = max_frames {
+        vm.limit_frames(frames);
+    }
+    if let Some(threshold) = jit {
+        vm.enable_jit(threshold);
+    }
+    if profile_out.is_some() {
+        vm.enable_profile();
+    }
+    if let Err(e) = vm.load_bytes(&read_file(path)) {
+        load_failed(path, e);
+    }
+    // `main` exists in a verified program
+    let result = vm.call("main", &[]);
+    if let (Some(file), Some(profile)) = (profile_out, vm.profile()) {
+        if let Err(e) = fs::write(&file, profile.to_string()) {
+            fail(format!("{}: {}", file, e));
+        }
+    }
+    if gc_stats {
+        eprintln!("{}", vm.memory().gc.stats);
+    }
+    if jit_stats {
+        match vm.compiled() {
+            [] => eprintln!("jit: no function compiled"),
+            names => eprintln!("jit: compiled {}", names.join(" ")),
+        }
+    }
+    if let Err(e) = result {
+        fail(format!("{}: {}", path, e));
+    }
+}
+
+fn debug(args : &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => fail("debug: expected one program".to_string()),
+    };
+    let program = load_program(path);
+    let host = host::Host::default();
+    check_program(path, &program, &host);
+    let image = match vm::Image::link(&program, &host) {
+        Ok(i) => i,
+        Err(e) => fail(format!("{}: {}", path, e)),
+    };
+    let machine = vm::Machine::new(&image, &host, mem_alloc::Memory::new());
+    let mut debugger = match debug::Debugger::new(&program, machine) {
+        Ok(d) => d,
+        Err(e) => fail(format!("{}: {}", path, e)),
+    };
+    let stdout = io::stdout();
+    let mut line = "where".to_string();
+    loop {
+        match debugger.command(&line, &mut stdout.lock()) {
+            Ok(true) => {}
+            _ => break,
+        }
+        print!("(rvmi) ");
+        let _ = stdout.lock().flush();
+        line.clear();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+}
+
+fn main() {
+    let args : Vec<String> = env::args().collect();
+    match args.get(1).map(|a| a.as_str()) {
+        None => print_help(),
+        Some("asm") => asm(&args[2..]),
+        Some("disasm") => disasm(&args[2..]),
+        Some("tac") => tac(&args[2..]),
+        Some("cfg") => cfg(&args[2..]),
+        Some("debug") => debug(&args[2..]),
+        Some(_) => run(&args[1..]),
+    }
 }
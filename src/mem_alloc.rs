@@ -1,19 +1,82 @@
-use std::collections::LinkedList;
+use super::error::Fault;
+use super::gc::{self, Gc};
 
-#[derive(Debug, Clone)]
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     TInt, TFloat, TString, Void,
-    TClass(String)
+    TClass(String),
+    /// an array of the element type, written with `[]` after it
+    TArray(Box<Type>)
 }
 
+/// deepest nesting of array types; walking a type recurses once per level
+pub const MAX_NESTING : usize = 32;
+
 impl Type {
-    pub fn from_string(s : String) -> Type {
-        match s.as_str() {
+    /**
+     * Wraps `elem` in `dims` array types.
+     */
+    pub fn array_of(elem : Type, dims : usize) -> Type {
+        let mut t = elem;
+        for _ in 0..dims {
+            t = Type::TArray(Box::new(t));
+        }
+        return t;
+    }
+}
+
+impl FromStr for Type {
+    type Err = ();
+
+    /**
+     * Parses a type as the raw encoding writes it. Fails on arrays nested
+     * deeper than `MAX_NESTING`.
+     */
+    fn from_str(s : &str) -> Result<Type, ()> {
+        let mut name = s;
+        let mut dims = 0;
+        while let Some(elem) = name.strip_suffix("[]") {
+            name = elem;
+            dims += 1;
+            if dims > MAX_NESTING {
+                return Err(());
+            }
+        }
+        let elem = match name {
             "int" => Type::TInt,
             "float" => Type::TFloat,
             "string" => Type::TString,
             "NULL" => Type::Void,
-            _ => Type::TClass(s),
+            _ => Type::TClass(name.to_string()),
+        };
+        return Ok(Type::array_of(elem, dims));
+    }
+}
+
+impl Type {
+    /**
+     * Number of values a function with this return type leaves behind.
+     */
+    pub fn arity(&self) -> usize {
+        return match self {
+            Type::Void => 0,
+            _ => 1,
+        };
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::TInt => write!(f, "int"),
+            Type::TFloat => write!(f, "float"),
+            Type::TString => write!(f, "string"),
+            Type::Void => write!(f, "NULL"),
+            Type::TClass(name) => write!(f, "{}", name),
+            Type::TArray(elem) => write!(f, "{}[]", elem),
         }
     }
 }
@@ -24,283 +87,342 @@ pub enum Atom {
     VInt(i32),
     VFloat(f32),
     VString(String),
+    /// only ever found on the heap
+    Object(Box<Object>),
+    /// only ever found on the heap
+    Array(Box<Array>),
     Null,
 }
 
+/**
+ * An instance of a class, with its fields in declaration order.
+ */
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub class  : String,
+    pub fields : Vec<Atom>
+}
+
+#[derive(Debug, Clone)]
+pub struct Array {
+    pub elem  : Type,
+    pub items : Vec<Atom>
+}
+
+/**
+ * An active call.
+ */
+#[derive(Debug)]
+pub struct Frame {
+    /// index of the function in the `vm::Image`
+    pub func   : usize,
+    /// index of the next instruction to run
+    pub pc     : usize,
+    /// the call's operand stack is `Memory.stack[base..]`
+    pub base   : usize,
+    pub locals : Vec<Atom>
+}
+
+/**
+ * The state of a running program.
+ *
+ * `stack` holds the operand stacks of all active calls, one above the
+ * other. `frames` holds the active calls, the innermost last; `globals`
+ * is the area shared by all of them. `heap` holds the objects and arrays
+ * that `Atom::Ref`s refer to, and `free` the addresses of its cells that
+ * `gc::collect` has freed.
+ */
 #[derive(Debug)]
 pub struct Memory {
-    pub stack : LinkedList<Atom>,
-    pub heap : Vec<Atom>
+    pub stack : Vec<Atom>,
+    pub frames : Vec<Frame>,
+    pub globals : Vec<Atom>,
+    pub heap : Vec<Atom>,
+    pub free : Vec<usize>,
+    pub gc : Gc
 }
 
 impl Memory {
     pub fn new() -> Memory {
-        return Memory{stack: LinkedList::new(), heap: Vec::new()};
+        return Memory{
+            stack: Vec::new(), frames: Vec::new(),
+            globals: Vec::new(), heap: Vec::new(),
+            free: Vec::new(), gc: Gc::default()
+        };
+    }
+
+    /**
+     * Stores `val` in a free heap cell, or in a new one, and returns its
+     * address.
+     */
+    pub fn alloc(heap : &mut Vec<Atom>, free : &mut Vec<usize>, gc : &mut Gc, val : Atom) -> usize {
+        gc.allocated(gc::size(&val));
+        let r = match free.pop() {
+            Some(r) => {
+                heap[r] = val;
+                r
+            }
+            None => {
+                heap.push(val);
+                heap.len() - 1
+            }
+        };
+        gc.stats.peak = gc.stats.peak.max(gc.used());
+        return r;
+    }
+
+    /**
+     * Writes `val` to `slots[i]`, growing the slots as needed.
+     */
+    pub fn set_slot(slots : &mut Vec<Atom>, i : usize, val : Atom) {
+        while i >= slots.len() {
+            slots.push(Atom::Null);
+        }
+        slots[i] = val;
+    }
+
+    /**
+     * Reads `slots[i]`, which is `None` if it has never been written.
+     */
+    pub fn get_slot(slots : &[Atom], i : usize) -> Option<Atom> {
+        return match slots.get(i) {
+            Some(Atom::Null) | None => None,
+            Some(val) => Some(val.clone()),
+        };
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        return Memory::new();
     }
 }
 
 impl Atom {
-    pub fn plus(&self, b : Atom) -> Atom {
+    /**
+     * Whether the value can be of type `t`, not looking at what a
+     * reference refers to: see `vm::Image::conforms` for that.
+     */
+    pub fn has_type(&self, t : &Type) -> bool {
+        return matches!((self, t),
+            (Atom::VInt(_), Type::TInt) |
+            (Atom::VFloat(_), Type::TFloat) |
+            (Atom::VString(_), Type::TString) |
+            (Atom::Ref(_), Type::TClass(_)) |
+            (Atom::Ref(_), Type::TArray(_))
+        );
+    }
+
+    /**
+     * The value a new array holds for elements of type `t`: zero for
+     * numbers, the empty string, and unset for references.
+     */
+    pub fn zero(t : &Type) -> Atom {
+        return match t {
+            Type::TInt => Atom::VInt(0),
+            Type::TFloat => Atom::VFloat(0.0),
+            Type::TString => Atom::VString(String::new()),
+            _ => Atom::Null,
+        };
+    }
+
+    pub fn plus(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt(l + r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt(l.wrapping_add(r))),
+                    _ => Err(Fault::TypeMismatch("+", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VFloat(l + r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VFloat(l + r)),
+                    _ => Err(Fault::TypeMismatch("+", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("+", self.clone()))
         }
     }
 
-    pub fn minus(&self, b : Atom) -> Atom {
+    pub fn minus(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt(l - r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt(l.wrapping_sub(r))),
+                    _ => Err(Fault::TypeMismatch("-", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VFloat(l - r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VFloat(l - r)),
+                    _ => Err(Fault::TypeMismatch("-", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("-", self.clone()))
         }
     }
 
-    pub fn mult(&self, b : Atom) -> Atom {
+    pub fn mult(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt(l * r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt(l.wrapping_mul(r))),
+                    _ => Err(Fault::TypeMismatch("*", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VFloat(l * r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VFloat(l * r)),
+                    _ => Err(Fault::TypeMismatch("*", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("*", self.clone()))
         }
     }
 
-    pub fn div(&self, b : Atom) -> Atom {
+    pub fn div(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt(l / r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(0) => Err(Fault::DivisionByZero),
+                    Atom::VInt(r) => Ok(Atom::VInt(l.wrapping_div(r))),
+                    _ => Err(Fault::TypeMismatch("/", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VFloat(l / r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VFloat(l / r)),
+                    _ => Err(Fault::TypeMismatch("/", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("/", self.clone()))
         }
     }
 
-    pub fn rem(&self, b : Atom) -> Atom {
+    pub fn rem(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt(l % r),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(0) => Err(Fault::DivisionByZero),
+                    Atom::VInt(r) => Ok(Atom::VInt(l.wrapping_rem(r))),
+                    _ => Err(Fault::TypeMismatch("%", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("%", self.clone()))
         }
     }
 
-    pub fn gt(&self, b : Atom) -> Atom {
+    pub fn gt(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt((*l > r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt((*l > r) as i32)),
+                    _ => Err(Fault::TypeMismatch(">", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VInt((*l > r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VInt((*l > r) as i32)),
+                    _ => Err(Fault::TypeMismatch(">", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented(">", self.clone()))
         }
     }
 
-    pub fn ge(&self, b : Atom) -> Atom {
+    pub fn ge(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt((*l >= r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt((*l >= r) as i32)),
+                    _ => Err(Fault::TypeMismatch(">=", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VInt((*l >= r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VInt((*l >= r) as i32)),
+                    _ => Err(Fault::TypeMismatch(">=", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented(">=", self.clone()))
         }
     }
 
-    pub fn lt(&self, b : Atom) -> Atom {
+    pub fn lt(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt((*l < r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt((*l < r) as i32)),
+                    _ => Err(Fault::TypeMismatch("<", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VInt((*l < r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VInt((*l < r) as i32)),
+                    _ => Err(Fault::TypeMismatch("<", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("<", self.clone()))
         }
     }
 
-    pub fn le(&self, b : Atom) -> Atom {
+    pub fn le(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt((*l <= r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt((*l <= r) as i32)),
+                    _ => Err(Fault::TypeMismatch("<=", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VInt((*l <= r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VInt((*l <= r) as i32)),
+                    _ => Err(Fault::TypeMismatch("<=", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("<=", self.clone()))
         }
     }
 
-    pub fn eq(&self, b : Atom) -> Atom {
+    pub fn eq(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt((*l == r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt((*l == r) as i32)),
+                    _ => Err(Fault::TypeMismatch("==", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VInt((*l == r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VInt((*l == r) as i32)),
+                    _ => Err(Fault::TypeMismatch("==", self.clone(), b))
                 }
             }
             Atom::VString(l) => {
                 match b {
-                    Atom::VString(r) => Atom::VInt((*l == r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VString(r) => Ok(Atom::VInt((*l == r) as i32)),
+                    _ => Err(Fault::TypeMismatch("==", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("==", self.clone()))
         }
     }
 
-    pub fn ne(&self, b : Atom) -> Atom {
+    pub fn ne(&self, b : Atom) -> Result<Atom, Fault> {
         match self {
             Atom::VInt(l) => {
                 match b {
-                    Atom::VInt(r) => Atom::VInt((*l != r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VInt(r) => Ok(Atom::VInt((*l != r) as i32)),
+                    _ => Err(Fault::TypeMismatch("!=", self.clone(), b))
                 }
             }
             Atom::VFloat(l) => {
                 match b {
-                    Atom::VFloat(r) => Atom::VInt((*l != r) as i32),
-                    _ => panic! {
-                        "+: type mismatch!\nl={:?}\nr={:?}", self, b
-                    }
+                    Atom::VFloat(r) => Ok(Atom::VInt((*l != r) as i32)),
+                    _ => Err(Fault::TypeMismatch("!=", self.clone(), b))
                 }
             }
-            _ => panic! {
-                "+: not implemented for {:?}", self
-            }
+            _ => Err(Fault::NotImplemented("!=", self.clone()))
         }
     }
 }
@@ -0,0 +1,532 @@
+/*!
+ * Optimizations over the instructions of a verified program.
+ *
+ * `-O1` runs the local passes below until none of them changes anything:
+ * constant folding, `dup; pop` removal, jump threading, removal of code
+ * after a `goto` or `ret` and of labels nothing jumps to. `-O2` also
+ * removes every block the control-flow graph cannot reach, and first
+ * inlines calls of small functions.
+ *
+ * The passes keep `Exec::rows` and `Exec::labels` in step with the
+ * instructions.
+ */
+
+use super::scanner::Token;
+use super::ir::{DeFun, Exec, Program};
+use super::mem_alloc::Atom;
+use super::cfg::Cfg;
+use super::verify::MAX_LOCALS;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// the optimization level used when none is given
+pub const DEFAULT_LEVEL : u8 = 0;
+pub const MAX_LEVEL : u8 = 2;
+
+/// instructions, not counting labels, of the largest function inlined
+pub const DEFAULT_INLINE_SIZE : usize = 16;
+
+/// calls a site needs in a profile to inline functions up to four times
+/// the size limit there
+pub const HOT_CALLS : u64 = 100;
+
+/**
+ * How often each call site ran, keyed by the calling function, the row
+ * of the `call` and the called function. Rows survive optimization, so a
+ * profile taken at one level applies at any other.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub calls : HashMap<(String, usize, String), u64>
+}
+
+impl Profile {
+    /**
+     * Reads a profile written by its `Display`, one call site per line.
+     */
+    pub fn parse(text : &str) -> Result<Profile, String> {
+        let mut profile = Profile::default();
+        for (i, line) in text.lines().enumerate() {
+            let words : Vec<&str> = line.split_whitespace().collect();
+            let site = match words.as_slice() {
+                [] => continue,
+                [caller, row, callee, count] => {
+                    match (row.parse(), count.parse()) {
+                        (Ok(row), Ok(count)) => Some((caller, row, callee, count)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            match site {
+                Some((caller, row, callee, count)) => {
+                    profile.calls.insert((caller.to_string(), row, callee.to_string()), count);
+                }
+                None => return Err(format!("{}: expected `caller row callee count`", i + 1)),
+            }
+        }
+        return Ok(profile);
+    }
+
+    pub fn count(&self, caller : &str, row : usize, callee : &str) -> u64 {
+        let key = (caller.to_string(), row, callee.to_string());
+        return self.calls.get(&key).cloned().unwrap_or(0);
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let mut sites : Vec<_> = self.calls.iter().collect();
+        sites.sort();
+        for ((caller, row, callee), count) in sites {
+            writeln!(f, "{} {} {} {}", caller, row, callee, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// from 0 (not at all) to `MAX_LEVEL`
+    pub level       : u8,
+    pub inline_size : usize,
+    /// call counts to inline by; without them every call of a small
+    /// enough function is inlined
+    pub profile     : Option<Profile>
+}
+
+impl Options {
+    pub fn new(level : u8) -> Options {
+        return Options {level, inline_size: DEFAULT_INLINE_SIZE, profile: None};
+    }
+}
+
+/**
+ * Keeps the instructions of `exec` for which `keep` is set.
+ */
+fn retain(exec : &mut Exec, keep : &[bool]) {
+    let mut pc = 0;
+    exec.tokens.retain(|_| {
+        pc += 1;
+        return keep[pc - 1];
+    });
+    let mut pc = 0;
+    exec.rows.retain(|_| {
+        pc += 1;
+        return keep[pc - 1];
+    });
+    relabel(exec);
+}
+
+/**
+ * Rebuilds `exec.labels` after instructions have moved.
+ */
+fn relabel(exec : &mut Exec) {
+    exec.labels.clear();
+    for (pc, token) in exec.tokens.iter().enumerate() {
+        if let Token::Label(lbl) = token {
+            exec.labels.insert(*lbl, pc);
+        }
+    }
+}
+
+/**
+ * Applies the binary instruction `token` the way the interpreter would,
+ * with `a` on top of the stack. `None` if it faults or is not binary.
+ */
+fn apply(token : &Token, a : i32, b : i32) -> Option<i32> {
+    let (a, b) = (Atom::VInt(a), Atom::VInt(b));
+    let val = match token {
+        Token::Add => a.plus(b),
+        Token::Sub => a.minus(b),
+        Token::Mul => a.mult(b),
+        Token::Div => a.div(b),
+        Token::Rem => a.rem(b),
+        Token::Eq => a.eq(b),
+        Token::Ne => a.ne(b),
+        Token::Lt => a.lt(b),
+        Token::Le => a.le(b),
+        Token::Gt => a.gt(b),
+        Token::Ge => a.ge(b),
+        _ => return None,
+    };
+    return match val {
+        Ok(Atom::VInt(i)) => Some(i),
+        _ => None,
+    };
+}
+
+/**
+ * Folds `pushi b; pushi a; op` into a single `pushi`. Operations that
+ * would fault, like a division by zero, are left to fault at runtime.
+ */
+fn fold_constants(exec : &mut Exec) -> bool {
+    let mut changed = false;
+    let mut pc = 0;
+    while pc + 2 < exec.tokens.len() {
+        let folded = match &exec.tokens[pc..pc + 3] {
+            [Token::Pushi(b), Token::Pushi(a), op] => apply(op, *a, *b),
+            _ => None,
+        };
+        match folded {
+            Some(val) => {
+                exec.tokens[pc] = Token::Pushi(val);
+                exec.tokens.drain(pc + 1..pc + 3);
+                exec.rows.drain(pc + 1..pc + 3);
+                changed = true;
+                // the result may fold with the push before it
+                pc = pc.saturating_sub(1);
+            }
+            None => pc += 1,
+        }
+    }
+    if changed {
+        relabel(exec);
+    }
+    return changed;
+}
+
+fn drop_dup_pop(exec : &mut Exec) -> bool {
+    let mut keep = vec![true; exec.tokens.len()];
+    let mut pc = 0;
+    while pc + 1 < exec.tokens.len() {
+        if let [Token::Dup, Token::Pop] = &exec.tokens[pc..pc + 2] {
+            keep[pc] = false;
+            keep[pc + 1] = false;
+            pc += 2;
+        } else {
+            pc += 1;
+        }
+    }
+    if keep.iter().all(|k| *k) {
+        return false;
+    }
+    retain(exec, &keep);
+    return true;
+}
+
+/**
+ * The label a jump to `lbl` ends up at, following `goto`s at its target.
+ */
+fn final_target(exec : &Exec, lbl : usize) -> usize {
+    let mut seen = HashSet::new();
+    let mut lbl = lbl;
+    while seen.insert(lbl) {
+        let mut pc = match exec.labels.get(&lbl) {
+            Some(pc) => *pc,
+            None => break,
+        };
+        while let Some(Token::Label(_)) = exec.tokens.get(pc) {
+            pc += 1;
+        }
+        match exec.tokens.get(pc) {
+            Some(Token::Goto(next)) => lbl = *next,
+            _ => break,
+        }
+    }
+    return lbl;
+}
+
+/**
+ * Retargets jumps to `goto`s, and drops `goto`s to the label right after
+ * them.
+ */
+fn thread_jumps(exec : &mut Exec) -> bool {
+    let mut changed = false;
+    for pc in 0..exec.tokens.len() {
+        let target = match &exec.tokens[pc] {
+            Token::Goto(lbl) | Token::Branch(lbl) => final_target(exec, *lbl),
+            _ => continue,
+        };
+        match &mut exec.tokens[pc] {
+            Token::Goto(lbl) | Token::Branch(lbl) if *lbl != target => {
+                *lbl = target;
+                changed = true;
+            }
+            _ => {}
+        }
+    }
+
+    let mut keep = vec![true; exec.tokens.len()];
+    for (pc, token) in exec.tokens.iter().enumerate() {
+        if let Token::Goto(lbl) = token {
+            let mut next = pc + 1;
+            while let Some(Token::Label(l)) = exec.tokens.get(next) {
+                if l == lbl {
+                    keep[pc] = false;
+                    break;
+                }
+                next += 1;
+            }
+        }
+    }
+    if keep.iter().any(|k| ! *k) {
+        retain(exec, &keep);
+        changed = true;
+    }
+    return changed;
+}
+
+/**
+ * Drops the instructions between a `goto` or `ret` and the next label.
+ */
+fn drop_after_jump(exec : &mut Exec) -> bool {
+    let mut keep = vec![true; exec.tokens.len()];
+    let mut dead = false;
+    for (pc, token) in exec.tokens.iter().enumerate() {
+        match token {
+            Token::Label(_) => dead = false,
+            _ if dead => keep[pc] = false,
+            Token::Goto(_) | Token::Ret => dead = true,
+            _ => {}
+        }
+    }
+    if keep.iter().all(|k| *k) {
+        return false;
+    }
+    retain(exec, &keep);
+    return true;
+}
+
+fn drop_unused_labels(exec : &mut Exec) -> bool {
+    let targets : HashSet<usize> = exec.tokens.iter().filter_map(|token| match token {
+        Token::Goto(lbl) | Token::Branch(lbl) => Some(*lbl),
+        _ => None,
+    }).collect();
+    let keep : Vec<bool> = exec.tokens.iter().map(|token| match token {
+        Token::Label(lbl) => targets.contains(lbl),
+        _ => true,
+    }).collect();
+    if keep.iter().all(|k| *k) {
+        return false;
+    }
+    retain(exec, &keep);
+    return true;
+}
+
+/**
+ * Drops the blocks no path from the entry reaches.
+ */
+fn drop_unreachable(exec : &mut Exec, cfg : &Cfg) -> bool {
+    let mut keep = vec![true; exec.tokens.len()];
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        if ! cfg.reachable(id) {
+            for k in &mut keep[block.start..block.end] {
+                *k = false;
+            }
+        }
+    }
+    if keep.iter().all(|k| *k) {
+        return false;
+    }
+    retain(exec, &keep);
+    return true;
+}
+
+/**
+ * Whether `name` can call itself, directly or not.
+ */
+fn recursive(prog : &Program, name : &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut work = vec![name];
+    while let Some(caller) = work.pop() {
+        let def = match prog.func.defs.iter().find(|d| d.name == caller) {
+            Some(d) => d,
+            None => continue,
+        };
+        for token in &def.exec.tokens {
+            if let Token::Call(callee) = token {
+                if callee == name {
+                    return true;
+                }
+                if seen.insert(callee.as_str()) {
+                    work.push(callee);
+                }
+            }
+        }
+    }
+    return false;
+}
+
+/**
+ * Whether `def` stores every local before it loads it, on every path.
+ * Inlined code keeps its locals from one call to the next, so a load
+ * that would fail on an unset variable must not be inlined.
+ */
+fn stores_before_loads(def : &DeFun) -> bool {
+    let exec = &def.exec;
+    let mut stored : Vec<Option<HashSet<usize>>> = vec![None; exec.tokens.len() + 1];
+    let mut work = vec![(0, HashSet::new())];
+    while let Some((pc, set)) = work.pop() {
+        let set = match &stored[pc] {
+            Some(known) if known.is_subset(&set) => continue,
+            Some(known) => known.intersection(&set).cloned().collect(),
+            None => set,
+        };
+        stored[pc] = Some(set.clone());
+        let mut set = set;
+        match exec.tokens.get(pc) {
+            None | Some(Token::Ret) => {}
+            Some(Token::Load(i)) if ! set.contains(i) => return false,
+            Some(Token::Store(i)) | Some(Token::Stores(i, _)) => {
+                set.insert(*i);
+                work.push((pc + 1, set));
+            }
+            Some(Token::Goto(lbl)) => work.push((exec.labels[lbl], set)),
+            Some(Token::Branch(lbl)) => {
+                work.push((exec.labels[lbl], set.clone()));
+                work.push((pc + 1, set));
+            }
+            Some(_) => work.push((pc + 1, set)),
+        }
+    }
+    return true;
+}
+
+fn size(def : &DeFun) -> usize {
+    return def.exec.tokens.iter().filter(|t| ! matches!(t, Token::Label(_))).count();
+}
+
+fn should_inline(opts : &Options, caller : &str, row : usize, callee : &DeFun) -> bool {
+    let size = size(callee);
+    return match &opts.profile {
+        None => size <= opts.inline_size,
+        Some(profile) => {
+            let calls = profile.count(caller, row, &callee.name);
+            calls > 0 && (size <= opts.inline_size ||
+                calls >= HOT_CALLS && size <= 4 * opts.inline_size)
+        }
+    };
+}
+
+/**
+ * Replaces calls of small, non-recursive functions by their bodies. The
+ * callee's locals move past those of the caller, its labels get fresh
+ * numbers and its `ret`s jump to the end of the inlined code. All
+ * inlined code of a caller shares the same locals, since each of them
+ * stores a local before it loads it.
+ *
+ * The arguments of an inlined call are no longer checked at runtime
+ * against the parameter types, only statically by `typeck`.
+ */
+/// the largest label the byte encoding holds
+const MAX_LABEL : usize = u32::MAX as usize;
+
+/**
+ * The number of locals `def` uses, `None` if it does not fit a `usize`.
+ */
+fn locals_used(def : &DeFun) -> Option<usize> {
+    let mut n = 0;
+    for token in &def.exec.tokens {
+        if let Token::Load(i) | Token::Store(i) | Token::Stores(i, _) = token {
+            n = n.max(i.checked_add(1)?);
+        }
+    }
+    return Some(n);
+}
+
+fn inline_calls(prog : &mut Program, opts : &Options) {
+    let original = prog.clone();
+    let inlinable : HashSet<&str> = original.func.defs.iter()
+        .filter(|d| ! recursive(&original, &d.name) && stores_before_loads(d))
+        .map(|d| d.name.as_str())
+        .collect();
+
+    for def in &mut prog.func.defs {
+        let nlocals = match locals_used(def) {
+            Some(n) => n,
+            None => continue,
+        };
+        let exec = &mut def.exec;
+        let mut next_label = match exec.labels.keys().max() {
+            Some(l) if *l >= MAX_LABEL => continue,
+            Some(l) => l + 1,
+            None => 0,
+        };
+
+        let mut tokens = Vec::new();
+        let mut rows = Vec::new();
+        for (token, row) in exec.tokens.iter().zip(&exec.rows) {
+            let callee = match token {
+                Token::Call(name) if inlinable.contains(name.as_str()) => {
+                    original.func.defs.iter().find(|d| d.name == *name)
+                }
+                _ => None,
+            };
+            // the callee takes locals after those of the caller, and a
+            // label for its end and each of its own
+            let fits = |g : &DeFun| {
+                locals_used(g).and_then(|n| n.checked_add(nlocals)).is_some_and(|n| n <= MAX_LOCALS)
+                    && next_label.checked_add(g.exec.labels.len()).is_some_and(|l| l <= MAX_LABEL)
+            };
+            let callee = match callee {
+                Some(g) if should_inline(opts, &def.name, *row, g) && fits(g) => g,
+                _ => {
+                    tokens.push(token.clone());
+                    rows.push(*row);
+                    continue;
+                }
+            };
+
+            let end = next_label;
+            next_label += 1;
+            let mut renamed = HashMap::new();
+            let mut rename = |lbl : &usize| {
+                return *renamed.entry(*lbl).or_insert_with(|| {
+                    next_label += 1;
+                    return next_label - 1;
+                });
+            };
+            for token in &callee.exec.tokens {
+                tokens.push(match token {
+                    Token::Label(lbl) => Token::Label(rename(lbl)),
+                    Token::Goto(lbl) => Token::Goto(rename(lbl)),
+                    Token::Branch(lbl) => Token::Branch(rename(lbl)),
+                    Token::Load(i) => Token::Load(i + nlocals),
+                    Token::Store(i) => Token::Store(i + nlocals),
+                    Token::Stores(i, string) => Token::Stores(i + nlocals, string.clone()),
+                    Token::Ret => Token::Goto(end),
+                    t => t.clone(),
+                });
+                rows.push(*row);
+            }
+            tokens.push(Token::Label(end));
+            rows.push(*row);
+        }
+        exec.tokens = tokens;
+        exec.rows = rows;
+        relabel(exec);
+    }
+}
+
+/**
+ * Optimizes every function of a verified program.
+ */
+pub fn optimize(prog : &mut Program, opts : &Options) {
+    let level = opts.level;
+    if level == 0 {
+        return;
+    }
+    if level >= 2 {
+        inline_calls(prog, opts);
+    }
+    for def in &mut prog.func.defs {
+        loop {
+            let mut changed = false;
+            if level >= 2 {
+                let cfg = Cfg::build(def);
+                changed |= drop_unreachable(&mut def.exec, &cfg);
+            }
+            let exec = &mut def.exec;
+            changed |= fold_constants(exec);
+            changed |= drop_dup_pop(exec);
+            changed |= thread_jumps(exec);
+            changed |= drop_after_jump(exec);
+            changed |= drop_unused_labels(exec);
+            if ! changed {
+                break;
+            }
+        }
+    }
+}
@@ -1,29 +1,187 @@
-use std::io::Write;
+/*!
+ * The builtins, and the console they read from and write to.
+ */
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
 use super::mem_alloc::*;
+use super::error::Fault;
+use super::host::{Host, Signature};
+
+/**
+ * The streams `print` and `println` write to and `readint` reads from,
+ * the standard ones unless the embedding program gives others.
+ *
+ * The input is read a byte at a time and never past the integer read, so
+ * that the rest is left to whoever else reads the stream, like the
+ * commands of `rvmi debug` on the standard input. Wrap a slow stream in a
+ * `BufReader` if nobody else reads it.
+ */
+pub struct Console {
+    input  : Box<dyn Read>,
+    output : Box<dyn Write>
+}
+
+impl Console {
+    pub fn new(input : Box<dyn Read>, output : Box<dyn Write>) -> Console {
+        return Console {input, output};
+    }
+
+    pub fn stdio() -> Console {
+        return Console::new(Box::new(io::stdin()), Box::new(io::stdout()));
+    }
+
+    pub fn input(&mut self) -> &mut dyn Read {
+        return &mut self.input;
+    }
+
+    pub fn output(&mut self) -> &mut dyn Write {
+        return &mut self.output;
+    }
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        return Console::stdio();
+    }
+}
 
-pub fn print(mut atom : Atom, mut mem : Box<Memory>) -> Box<Memory> {
+impl fmt::Debug for Console {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Console")
+    }
+}
+
+/**
+ * An output stream that keeps what is written to it, shared by its
+ * clones, so that a clone given to a `Console` can be read from another.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Buffer {
+    data : Rc<RefCell<Vec<u8>>>
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        return Buffer::default();
+    }
+
+    /**
+     * Everything written so far, with invalid UTF-8 replaced.
+     */
+    pub fn contents(&self) -> String {
+        return String::from_utf8_lossy(&self.data.borrow()).into_owned();
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        self.data.borrow_mut().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+/**
+ * Registers the builtins `print`, `println` and `readint` with `host`.
+ */
+pub fn register(host : &mut Host) {
+    host.register("print", Signature::any(1, Type::Void), |args, ctx| {
+        let out = ctx.io.output();
+        print(&args[0], ctx.heap, out)?;
+        out.flush().map_err(io_fault)?;
+        return Ok(Atom::Null);
+    });
+    host.register("println", Signature::any(1, Type::Void), |args, ctx| {
+        let out = ctx.io.output();
+        print(&args[0], ctx.heap, out)?;
+        writeln!(out).and_then(|_| out.flush()).map_err(io_fault)?;
+        return Ok(Atom::Null);
+    });
+    host.register("readint", Signature::new(Vec::new(), Type::TInt), |_, ctx| {
+        return readint(ctx.io.input());
+    });
+}
+
+fn io_fault(e : io::Error) -> Fault {
+    return Fault::Io(e.to_string());
+}
+
+/**
+ * Names the object or array at heap address `r` with its address, like
+ * `Point@3` or `int[]@4`.
+ */
+fn describe(r : usize, heap : &[Atom]) -> String {
+    return match heap.get(r) {
+        Some(Atom::Object(obj)) => format!("{}@{}", obj.class, r),
+        Some(Atom::Array(arr)) => format!("{}[]@{}", arr.elem, r),
+        _ => format!("@{}", r),
+    };
+}
+
+/**
+ * Prints a value to `out`. An array is printed with its elements, but the
+ * objects and arrays it refers to only by `describe`, so that printing
+ * ends on cyclic data.
+ */
+pub fn print(atom : &Atom, heap : &[Atom], out : &mut dyn Write) -> Result<(), Fault> {
     match atom {
         Atom::Ref(r) => {
-            atom = mem.heap[r].clone();
-            mem = print(atom, mem);
-        }
-        Atom::VInt(val) => {
-            print!("{}", val);
-        }
-        Atom::VFloat(val) => {
-            print!("{}", val);
-        }
-        Atom::VString(val) => {
-            print!("{}", val);
-        }
-        Atom::Null => {
-            print!("Null");
+            match heap.get(*r) {
+                Some(Atom::Object(_)) => write!(out, "{}", describe(*r, heap)).map_err(io_fault)?,
+                Some(Atom::Array(arr)) => {
+                    write!(out, "[").map_err(io_fault)?;
+                    for (i, item) in arr.items.iter().enumerate() {
+                        if i > 0 {
+                            write!(out, ", ").map_err(io_fault)?;
+                        }
+                        match item {
+                            Atom::Ref(r) => write!(out, "{}", describe(*r, heap)).map_err(io_fault)?,
+                            _ => print(item, heap, out)?,
+                        }
+                    }
+                    write!(out, "]").map_err(io_fault)?;
+                }
+                Some(a) => print(a, heap, out)?,
+                None => return Err(Fault::UnsetVariable(*r)),
+            }
         }
+        Atom::VInt(val) => write!(out, "{}", val).map_err(io_fault)?,
+        Atom::VFloat(val) => write!(out, "{}", val).map_err(io_fault)?,
+        Atom::VString(val) => write!(out, "{}", val).map_err(io_fault)?,
+        Atom::Object(obj) => write!(out, "{}", obj.class).map_err(io_fault)?,
+        Atom::Array(arr) => write!(out, "{}[]", arr.elem).map_err(io_fault)?,
+        Atom::Null => write!(out, "Null").map_err(io_fault)?,
     }
-    std::io::stdout().flush().unwrap();
-    return mem;
+    return Ok(());
 }
 
-pub fn readint() -> Atom {
-    return Atom::VInt(read!());
+/**
+ * Reads an integer from `input`, skipping the whitespace before it and
+ * consuming the whitespace character after it.
+ */
+pub fn readint(input : &mut dyn Read) -> Result<Atom, Fault> {
+    let mut word = Vec::new();
+    // a byte at a time, to leave the rest of the input to whoever reads
+    // it next; `io::stdin` buffers underneath
+    #[allow(clippy::unbuffered_bytes)]
+    for byte in input.bytes() {
+        let byte = byte.map_err(io_fault)?;
+        if ! byte.is_ascii_whitespace() {
+            word.push(byte);
+        } else if ! word.is_empty() {
+            break;
+        }
+    }
+    let val = std::str::from_utf8(&word).ok().and_then(|w| w.parse().ok());
+    return match val {
+        Some(i) => Ok(Atom::VInt(i)),
+        None => Err(Fault::BadInput("expected an integer".to_string())),
+    };
 }
@@ -1,185 +1,395 @@
 use super::mem_alloc::Type;
+use super::error::ParseError;
+
+use std::fmt;
 
 #[derive(Debug, Clone)]
 pub enum Token {
     SRaw, SClass, SFn,
     Defun(String, Vec<Type>, Type), Endef,
-    Defcl(String), Endcl,
+    Defcl(String), Extends(String), Field(String, Type), Method(String), Endcl,
+    New(String), Getfield(String, String), Setfield(String, String), Callm(String),
+    Newarr(Type), Aload, Astore, Alen,
     Pushi(i32), Pushf(f32), Pushv(usize), Pop,
     Load(usize), Store(usize),
+    Gload(usize), Gstore(usize),
     Stores(usize, String),
     Alias(usize, usize),
     Add, Sub, Mul, Div, Rem,
     Eq, Ne, Lt, Le, Gt, Ge,
-    Call(String), Dup,
+    Call(String), Ret, Dup,
     Label(usize), Goto(usize), Branch(usize)
 }
 
+/**
+ * Prints a token in its canonical raw encoding.
+ */
+impl fmt::Display for Token {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::SRaw => write!(f, ".raw"),
+            Token::SClass => write!(f, ".class"),
+            Token::SFn => write!(f, ".function"),
+            Token::Defun(name, par_ts, ret_t) => {
+                write!(f, "defun {} {}", name, par_ts.len())?;
+                for t in par_ts {
+                    write!(f, " {}", t)?;
+                }
+                write!(f, " {}", ret_t)
+            }
+            Token::Endef => write!(f, "endef"),
+            Token::Defcl(name) => write!(f, "defcl {}", name),
+            Token::Extends(parent) => write!(f, "extends {}", parent),
+            Token::Field(name, t) => write!(f, "field {} {}", name, t),
+            Token::Method(name) => write!(f, "method {}", name),
+            Token::Endcl => write!(f, "endcl"),
+            Token::New(class) => write!(f, "new {}", class),
+            Token::Getfield(class, field) => write!(f, "getfield {} {}", class, field),
+            Token::Setfield(class, field) => write!(f, "setfield {} {}", class, field),
+            Token::Callm(method) => write!(f, "callm {}", method),
+            Token::Newarr(t) => write!(f, "newarr {}", t),
+            Token::Aload => write!(f, "aload"),
+            Token::Astore => write!(f, "astore"),
+            Token::Alen => write!(f, "alen"),
+            Token::Pushi(val) => write!(f, "pushi {}", val),
+            Token::Pushf(val) => write!(f, "pushf {}", val),
+            Token::Pushv(var) => write!(f, "pushv {}", var),
+            Token::Pop => write!(f, "pop"),
+            Token::Load(var) => write!(f, "load {}", var),
+            Token::Store(var) => write!(f, "store {}", var),
+            Token::Gload(var) => write!(f, "gload {}", var),
+            Token::Gstore(var) => write!(f, "gstore {}", var),
+            Token::Stores(var, data) => write!(f, "stores {} {} \"{}\"", var, data.len(), data),
+            Token::Alias(var, heap) => write!(f, "alias {} {}", var, heap),
+            Token::Add => write!(f, "add"),
+            Token::Sub => write!(f, "sub"),
+            Token::Mul => write!(f, "mul"),
+            Token::Div => write!(f, "div"),
+            Token::Rem => write!(f, "rem"),
+            Token::Eq => write!(f, "eq"),
+            Token::Ne => write!(f, "ne"),
+            Token::Lt => write!(f, "lt"),
+            Token::Le => write!(f, "le"),
+            Token::Gt => write!(f, "gt"),
+            Token::Ge => write!(f, "ge"),
+            Token::Call(name) => write!(f, "call {}", name),
+            Token::Ret => write!(f, "ret"),
+            Token::Dup => write!(f, "dup"),
+            Token::Label(lbl) => write!(f, "label {}", lbl),
+            Token::Goto(lbl) => write!(f, "goto {}", lbl),
+            Token::Branch(lbl) => write!(f, "branch {}", lbl),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Inst {
     pub token : Token,
-    pub row   : usize
+    pub row   : usize,
+    pub col   : usize
+}
+
+/**
+ * A stream of instructions consumed by the IR builder.
+ *
+ * Implemented by `Scanner` for the raw encoding and by
+ * `bytecode::Decoder` for the byte encoding. Both the stream and the
+ * builder `report` errors, which are collected so that one pass over a
+ * malformed file lists every problem in it.
+ */
+pub trait Tokens {
+    fn peek(&mut self) -> Option<Inst>;
+    fn next(&mut self) -> Option<Inst>;
+    fn report(&mut self, err : ParseError);
+    fn take_errors(&mut self) -> Vec<ParseError>;
+    /// position of the end of input, for errors found there
+    fn end(&self) -> (usize, usize);
+}
+
+/**
+ * A word of the raw encoding with the position it starts at.
+ */
+struct Word {
+    text   : String,
+    line   : usize,
+    col    : usize,
+    quoted : bool
 }
 
 pub struct Scanner {
-    data  : String,
-    index : usize,
-    row   : usize,
-    curr  : Option<Inst>
+    data   : Vec<char>,
+    index  : usize,
+    row    : usize,
+    col    : usize,
+    curr   : Option<Inst>,
+    errors : Vec<ParseError>
 }
 
-const SEPS : [char; 3] = [' ', '\n', '\r'];
+/// the characters that separate words
+pub const SEPS : [char; 4] = [' ', '\t', '\n', '\r'];
+
+const MNEMONICS : &[&str] = &[
+    ".raw", ".class", ".function", "defun", "endef", "defcl", "extends", "field",
+    "method", "endcl", "new", "getfield", "setfield", "pushi", "pushf", "pushv", "pop",
+    "load", "store", "gload", "gstore", "stores", "alias", "call", "callm", "newarr",
+    "aload", "astore", "alen", "ret", "dup", "label", "goto", "branch",
+    "add", "sub", "mul", "div", "rem", "eq", "ne", "lt", "le", "gt", "ge",
+];
 
 impl Scanner {
     pub fn from_string(input : String) -> Scanner {
-        let mut s = Scanner {data: input,index: 0, row: 0, curr: None};
+        let mut s = Scanner {
+            data: input.chars().collect(), index: 0, row: 1, col: 1,
+            curr: None, errors: Vec::new()
+        };
         s.update();
         return s;
     }
 
-    fn next_word(&mut self) -> String {
-        if self.data.len() == self.index {
-            return String::new();
-        }
-        while SEPS.contains(& (self.data.chars().nth(self.index).unwrap())) {
-                if self.data.chars().nth(self.index).unwrap() == '\n' {
-                    self.row += 1;
-                }
-                self.index += 1;
-                if self.data.len() == self.index {
-                    return String::new();
-                }
+    fn bump(&mut self) -> char {
+        let c = self.data[self.index];
+        self.index += 1;
+        if c == '\n' {
+            self.row += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
         }
+        return c;
+    }
 
-        if self.data.len() == self.index {
-            return String::new();
+    fn next_word(&mut self) -> Option<Word> {
+        loop {
+            while self.index < self.data.len() && SEPS.contains(&self.data[self.index]) {
+                self.bump();
+            }
+            if self.index == self.data.len() {
+                return None;
+            }
+            if self.data[self.index] != ';' {
+                break;
+            }
+            while self.index < self.data.len() && self.data[self.index] != '\n' {
+                self.bump();
+            }
         }
 
-        if self.data.chars().nth(self.index).unwrap() == ';' {
-            while self.data.chars().nth(self.index).unwrap() != '\n' {
-                self.index += 1;
+        let mut word = Word {text: String::new(), line: self.row, col: self.col, quoted: false};
+        if self.data[self.index] == '"' {
+            word.quoted = true;
+            self.bump();
+            while self.index < self.data.len() && self.data[self.index] != '"' {
+                word.text.push(self.bump());
+            }
+            if self.index == self.data.len() {
+                self.errors.push(ParseError::new(
+                    self.row, self.col, "", "right quotation mark"
+                ));
+            } else {
+                self.bump();
+            }
+        } else {
+            while self.index < self.data.len() && ! SEPS.contains(&self.data[self.index]) {
+                word.text.push(self.bump());
             }
-            return self.next_word();
         }
+        return Some(word);
+    }
 
-        let mut result = String::new();
-
-        if self.data.chars().nth(self.index).unwrap() == '"' {
-            self.index += 1;
-            while self.data.chars().nth(self.index).unwrap() != '"' {
-                result.push(self.data.chars().nth(self.index).unwrap());
-                self.index += 1;
-                if self.data.len() == self.index {
-                    eprintln!("line {}: right quotation missing", self.row);
-                    return String::new();
+    /**
+     * Reads the operand of `mnemonic`, reporting it if it is missing or
+     * does not parse.
+     */
+    fn operand<T : std::str::FromStr>(&mut self, mnemonic : &Word, expected : &str) -> Option<T> {
+        let before = (self.index, self.row, self.col);
+        match self.next_word() {
+            Some(w) => {
+                match w.text.parse() {
+                    Ok(v) if ! w.quoted => Some(v),
+                    _ => {
+                        let expected = format!("{} after `{}`", expected, mnemonic.text);
+                        self.errors.push(ParseError::new(w.line, w.col, &w.text, &expected));
+                        // the operand is more likely missing than an
+                        // instruction misplaced, which is read next
+                        if ! w.quoted && MNEMONICS.contains(&w.text.as_str()) {
+                            (self.index, self.row, self.col) = before;
+                        }
+                        None
+                    }
                 }
             }
-            self.index += 1;
-
-            return result;
-        } else {
-            while ! SEPS.contains(& (self.data.chars().nth(self.index).unwrap())) {
-                result.push(self.data.chars().nth(self.index).unwrap());
-                self.index += 1;
+            None => {
+                let expected = format!("{} after `{}`", expected, mnemonic.text);
+                self.errors.push(ParseError::new(self.row, self.col, "", &expected));
+                None
             }
-    
-            return result;
         }
     }
-    
-    fn update(&mut self) {
-        let nt = self.next_word();
-        self.curr = match nt.as_str() {
-            "" => None,
-            ".raw" => Some(Inst {token: Token::SRaw, row: self.row}),
-            ".class" => Some(Inst {token: Token::SClass, row: self.row}),
-            ".function" => Some(Inst {token: Token::SFn, row: self.row}),
+
+    fn name(&mut self, mnemonic : &Word, expected : &str) -> Option<String> {
+        return self.operand(mnemonic, expected);
+    }
+
+    /**
+     * Reads an address operand, which the byte encoding holds in a `u16`.
+     */
+    fn addr(&mut self, mnemonic : &Word) -> Option<usize> {
+        let addr : u16 = self.operand(mnemonic, "an address")?;
+        return Some(addr as usize);
+    }
+
+    /**
+     * Reads a label operand, which the byte encoding holds in a `u32`.
+     */
+    fn label(&mut self, mnemonic : &Word) -> Option<usize> {
+        let label : u32 = self.operand(mnemonic, "a label")?;
+        return Some(label as usize);
+    }
+
+    /**
+     * Reads one instruction starting at mnemonic `w`. Problems are
+     * reported and yield `None`, so the caller can carry on with the
+     * next word.
+     */
+    fn token(&mut self, w : &Word) -> Option<Token> {
+        if w.quoted {
+            self.errors.push(ParseError::new(w.line, w.col, &w.text, "an instruction"));
+            return None;
+        }
+        let token = match w.text.as_str() {
+            ".raw" => Token::SRaw,
+            ".class" => Token::SClass,
+            ".function" => Token::SFn,
             "defun" => {
-                let name = self.next_word();
-                let pars : usize = self.next_word().parse().unwrap();
+                let name = self.name(w, "a function name")?;
+                let pars : usize = self.operand(w, "a parameter count")?;
                 let mut par_types : Vec<Type> = Vec::new();
                 for _ in 0..pars {
-                    par_types.push(Type::from_string(self.next_word()));
+                    par_types.push(self.operand(w, "a parameter type")?);
                 }
-                let ret_type = Type::from_string(self.next_word());
-                Some(Inst {token: Token::Defun(name, par_types, ret_type), row: self.row})
+                let ret_type = self.operand(w, "a return type")?;
+                Token::Defun(name, par_types, ret_type)
             }
-            "endef" => {
-                Some(Inst {token: Token::Endef, row: self.row})
+            "endef" => Token::Endef,
+            "defcl" => Token::Defcl(self.name(w, "a class name")?),
+            "field" => {
+                let name = self.name(w, "a field name")?;
+                Token::Field(name, self.operand(w, "a field type")?)
             }
-            "pushi" => {
-                let immediate : i32 = self.next_word().parse().unwrap();
-                Some(Inst {token: Token::Pushi(immediate), row: self.row})
+            "extends" => Token::Extends(self.name(w, "a class name")?),
+            "method" => Token::Method(self.name(w, "a method name")?),
+            "endcl" => Token::Endcl,
+            "new" => Token::New(self.name(w, "a class name")?),
+            "getfield" => {
+                let class = self.name(w, "a class name")?;
+                Token::Getfield(class, self.name(w, "a field name or index")?)
             }
-            "pushf" => {
-                let immediate : f32 = self.next_word().parse().unwrap();
-                Some(Inst {token: Token::Pushf(immediate), row: self.row})
+            "setfield" => {
+                let class = self.name(w, "a class name")?;
+                Token::Setfield(class, self.name(w, "a field name or index")?)
             }
-            "load" => {
-                let var : usize = self.next_word().parse().unwrap();
-                Some(Inst {token: Token::Load(var), row: self.row})
+            "pushi" => Token::Pushi(self.operand(w, "an integer")?),
+            "pushf" => Token::Pushf(self.operand(w, "a float")?),
+            "pushv" => Token::Pushv(self.addr(w)?),
+            "pop" => Token::Pop,
+            "load" => Token::Load(self.addr(w)?),
+            "store" => Token::Store(self.addr(w)?),
+            "gload" => Token::Gload(self.addr(w)?),
+            "gstore" => Token::Gstore(self.addr(w)?),
+            "stores" => {
+                let heap = self.addr(w)?;
+                let size : u16 = self.operand(w, "a string length")?;
+                // an unterminated string is reported already, and its
+                // length is then of no interest
+                let reported = self.errors.len();
+                match self.next_word() {
+                    Some(data) if data.text.len() == size as usize || self.errors.len() > reported => {
+                        Token::Stores(heap, data.text)
+                    }
+                    Some(data) => {
+                        let expected = format!("a string of {} bytes after `stores`", size);
+                        self.errors.push(ParseError::new(data.line, data.col, &data.text, &expected));
+                        return None;
+                    }
+                    None => {
+                        self.errors.push(ParseError::new(
+                            self.row, self.col, "", "a string after `stores`"
+                        ));
+                        return None;
+                    }
+                }
             }
-            "store" => {
-                let var : usize = self.next_word().parse().unwrap();
-                Some(Inst {token: Token::Store(var), row: self.row})
+            "alias" => {
+                let var = self.addr(w)?;
+                Token::Alias(var, self.addr(w)?)
+            }
+            "call" => Token::Call(self.name(w, "a function name")?),
+            "callm" => Token::Callm(self.name(w, "a method name")?),
+            "newarr" => Token::Newarr(self.operand(w, "an element type")?),
+            "aload" => Token::Aload,
+            "astore" => Token::Astore,
+            "alen" => Token::Alen,
+            "ret" => Token::Ret,
+            "dup" => Token::Dup,
+            "label" => Token::Label(self.label(w)?),
+            "goto" => Token::Goto(self.label(w)?),
+            "branch" => Token::Branch(self.label(w)?),
+            "add" => Token::Add,
+            "sub" => Token::Sub,
+            "mul" => Token::Mul,
+            "div" => Token::Div,
+            "rem" => Token::Rem,
+            "eq" => Token::Eq,
+            "ne" => Token::Ne,
+            "lt" => Token::Lt,
+            "le" => Token::Le,
+            "gt" => Token::Gt,
+            "ge" => Token::Ge,
+            _ => {
+                self.errors.push(ParseError::new(w.line, w.col, &w.text, "an instruction"));
+                return None;
             }
-            "stores" => {
-                let heap : usize = self.next_word().parse().unwrap();
-                let _size : usize = self.next_word().parse().unwrap();
-                let data = self.next_word();
-                Some(Inst {token: Token::Stores(heap, data), row: self.row})
-            }
-            "call" => {
-                let fun = self.next_word();
-                Some(Inst {token: Token::Call(fun), row: self.row})
-            }
-            "dup" => {
-                Some(Inst {token: Token::Dup, row: self.row})
-            }
-            "label" => {
-                let lbl : usize = self.next_word().parse().unwrap();
-                Some(Inst {token: Token::Label(lbl), row: self.row})
-            }
-            "goto" => {
-                let lbl : usize = self.next_word().parse().unwrap();
-                Some(Inst {token: Token::Goto(lbl), row : self.row})
-            }
-            "branch" => {
-                let lbl : usize = self.next_word().parse().unwrap();
-                Some(Inst {token: Token::Branch(lbl), row : self.row})
-            }
-            "add" => Some(Inst {token: Token::Add, row : self.row}),
-            "sub" => Some(Inst {token: Token::Sub, row : self.row}),
-            "mul" => Some(Inst {token: Token::Mul, row : self.row}),
-            "div" => Some(Inst {token: Token::Div, row : self.row}),
-            "rem" => Some(Inst {token: Token::Rem, row : self.row}),
-            "eq" => Some(Inst {token: Token::Eq, row : self.row}),
-            "ne" => Some(Inst {token: Token::Ne, row : self.row}),
-            "lt" => Some(Inst {token: Token::Lt, row : self.row}),
-            "le" => Some(Inst {token: Token::Le, row : self.row}),
-            "gt" => Some(Inst {token: Token::Gt, row : self.row}),
-            "ge" => Some(Inst {token: Token::Ge, row : self.row}),
-            _ => panic!{
-                "(scanner) line {}: {} is not a valid instruction", self.row, nt
-            },
         };
+        return Some(token);
+    }
+
+    fn update(&mut self) {
+        self.curr = None;
+        while let Some(w) = self.next_word() {
+            if let Some(token) = self.token(&w) {
+                self.curr = Some(Inst {token, row: w.line, col: w.col});
+                return;
+            }
+        }
     }
 
     pub fn has_next(&mut self) -> bool {
-        return match self.curr {
-            Some(_) => true,
-            None => false
-        };
+        return self.curr.is_some();
     }
+}
 
-    pub fn peek(&mut self) -> Option<Inst> {
+impl Tokens for Scanner {
+    fn peek(&mut self) -> Option<Inst> {
         return self.curr.clone();
     }
 
-    pub fn next(&mut self) -> Option<Inst> {
+    fn next(&mut self) -> Option<Inst> {
         let val = self.curr.clone();
-        // println!("{:?}", val);
         self.update();
         return val;
     }
+
+    fn report(&mut self, err : ParseError) {
+        self.errors.push(err);
+    }
+
+    fn take_errors(&mut self) -> Vec<ParseError> {
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.sort_by_key(|e| (e.line, e.column));
+        return errors;
+    }
+
+    fn end(&self) -> (usize, usize) {
+        return (self.row, self.col);
+    }
 }
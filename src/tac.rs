@@ -0,0 +1,590 @@
+/*!
+ * Three-address code.
+ *
+ * A register-based form of the stack IR: every instruction names the
+ * virtual registers it reads and writes, and a function is a list of
+ * basic blocks, each ending in exactly one terminator. `lower` translates
+ * a `DeFun` by running its operand stack symbolically, so the stack
+ * disappears from the result.
+ *
+ * There are three kinds of registers. `%lN` is local variable `N`, `%sN`
+ * is slot `N` of the operand stack where it crosses a block boundary
+ * (which includes the parameters on entry), and `%tN` is a temporary
+ * that is written once.
+ */
+
+use super::scanner::Token;
+use super::ir::{DeFun, Program};
+use super::mem_alloc::Type;
+use super::host::Host;
+use super::typeck;
+use super::cfg::Cfg;
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    Local(usize),
+    Stack(usize),
+    Temp(usize)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Int(i32),
+    Float(f32),
+    Str(String)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add, Sub, Mul, Div, Rem,
+    Eq, Ne, Lt, Le, Gt, Ge
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Copy(Reg, Operand),
+    /// `dst = lhs op rhs`, where `lhs` was the top of the stack
+    Binary(Reg, BinOp, Operand, Operand),
+    Call(Option<Reg>, String, Vec<Operand>),
+    /// a method call, the object it is called on being the first argument
+    Callm(Option<Reg>, String, Vec<Operand>),
+    Gload(Reg, usize),
+    Gstore(usize, Operand),
+    New(Reg, String),
+    /// `dst = obj.field`, with the class and field as written in the stack IR
+    Getfield(Reg, Operand, String, String),
+    /// `obj.field = val`
+    Setfield(Operand, String, String, Operand),
+    /// `dst = new elem[len]`
+    Newarr(Reg, Type, Operand),
+    /// `dst = arr[index]`
+    Aload(Reg, Operand, Operand),
+    /// `arr[index] = val`
+    Astore(Operand, Operand, Operand),
+    Alen(Reg, Operand)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Goto(usize),
+    /// jumps to the first block if the operand is not zero, else to the second
+    Branch(Operand, usize, usize),
+    Ret(Option<Operand>)
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub id    : usize,
+    pub insts : Vec<Inst>,
+    pub term  : Term
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name   : String,
+    pub params : Vec<Reg>,
+    pub ret_t  : Type,
+    /// reachable blocks in program order, the entry block first
+    pub blocks : Vec<Block>,
+    /// registers whose type is the same wherever they are written
+    pub types  : HashMap<Reg, Type>
+}
+
+impl BinOp {
+    fn from_token(token : &Token) -> Option<BinOp> {
+        return match token {
+            Token::Add => Some(BinOp::Add),
+            Token::Sub => Some(BinOp::Sub),
+            Token::Mul => Some(BinOp::Mul),
+            Token::Div => Some(BinOp::Div),
+            Token::Rem => Some(BinOp::Rem),
+            Token::Eq => Some(BinOp::Eq),
+            Token::Ne => Some(BinOp::Ne),
+            Token::Lt => Some(BinOp::Lt),
+            Token::Le => Some(BinOp::Le),
+            Token::Gt => Some(BinOp::Gt),
+            Token::Ge => Some(BinOp::Ge),
+            _ => None,
+        };
+    }
+
+    pub fn token(&self) -> Token {
+        return match self {
+            BinOp::Add => Token::Add,
+            BinOp::Sub => Token::Sub,
+            BinOp::Mul => Token::Mul,
+            BinOp::Div => Token::Div,
+            BinOp::Rem => Token::Rem,
+            BinOp::Eq => Token::Eq,
+            BinOp::Ne => Token::Ne,
+            BinOp::Lt => Token::Lt,
+            BinOp::Le => Token::Le,
+            BinOp::Gt => Token::Gt,
+            BinOp::Ge => Token::Ge,
+        };
+    }
+}
+
+impl Function {
+    /**
+     * The type of an operand, `None` if it is not the same on every path.
+     */
+    pub fn type_of(&self, op : &Operand) -> Option<Type> {
+        return match op {
+            Operand::Reg(r) => self.types.get(r).cloned(),
+            Operand::Int(_) => Some(Type::TInt),
+            Operand::Float(_) => Some(Type::TFloat),
+            Operand::Str(_) => Some(Type::TString),
+        };
+    }
+
+    /**
+     * A register with its type, as written where it is defined.
+     */
+    fn def(&self, r : &Reg) -> String {
+        return match self.types.get(r) {
+            Some(t) => format!("{} : {}", r, t),
+            None => format!("{} : any", r),
+        };
+    }
+
+    fn fmt_inst(&self, f : &mut fmt::Formatter, inst : &Inst) -> fmt::Result {
+        match inst {
+            Inst::Copy(dst, op) => write!(f, "{} = {}", self.def(dst), op),
+            Inst::Binary(dst, op, lhs, rhs) => {
+                write!(f, "{} = {} {}, {}", self.def(dst), op, lhs, rhs)
+            }
+            Inst::Call(dst, name, args) | Inst::Callm(dst, name, args) => {
+                if let Some(dst) = dst {
+                    write!(f, "{} = ", self.def(dst))?;
+                }
+                let args : Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                let mnemonic = if let Inst::Call(..) = inst { "call" } else { "callm" };
+                write!(f, "{} {}({})", mnemonic, name, args.join(", "))
+            }
+            Inst::Gload(dst, i) => write!(f, "{} = gload {}", self.def(dst), i),
+            Inst::Gstore(i, op) => write!(f, "gstore {}, {}", i, op),
+            Inst::New(dst, class) => write!(f, "{} = new {}", self.def(dst), class),
+            Inst::Getfield(dst, obj, class, field) => {
+                write!(f, "{} = getfield {}, {}.{}", self.def(dst), obj, class, field)
+            }
+            Inst::Setfield(obj, class, field, val) => {
+                write!(f, "setfield {}, {}.{}, {}", obj, class, field, val)
+            }
+            Inst::Newarr(dst, t, len) => write!(f, "{} = newarr {}, {}", self.def(dst), t, len),
+            Inst::Aload(dst, arr, i) => write!(f, "{} = aload {}, {}", self.def(dst), arr, i),
+            Inst::Astore(arr, i, val) => write!(f, "astore {}, {}, {}", arr, i, val),
+            Inst::Alen(dst, arr) => write!(f, "{} = alen {}", self.def(dst), arr),
+        }
+    }
+}
+
+/**
+ * Lowers one block at a time, keeping the operand stack as operands.
+ */
+struct Lowering<'a> {
+    def   : &'a DeFun,
+    prog  : &'a Program,
+    host  : &'a Host,
+    temps : usize,
+    stack : Vec<Operand>,
+    insts : Vec<Inst>
+}
+
+impl<'a> Lowering<'a> {
+    fn temp(&mut self) -> Reg {
+        self.temps += 1;
+        return Reg::Temp(self.temps - 1);
+    }
+
+    fn pop(&mut self) -> Operand {
+        return self.stack.pop().expect("operand stack of a verified function");
+    }
+
+    /**
+     * Copies local `i` wherever the stack still refers to it, before it
+     * is overwritten.
+     */
+    fn materialize(&mut self, i : usize) {
+        let local = Operand::Reg(Reg::Local(i));
+        if self.stack.contains(&local) {
+            let t = self.temp();
+            self.insts.push(Inst::Copy(t, local.clone()));
+            for op in self.stack.iter_mut().filter(|op| **op == local) {
+                *op = Operand::Reg(t);
+            }
+        }
+    }
+
+    /**
+     * Writes the stack to the `%s` registers a successor block reads it
+     * from. A stack entry other than its own `%s` register can only be a
+     * copy of a lower one that stays in place, so the order is free.
+     */
+    fn spill(&mut self) {
+        for (k, op) in self.stack.iter().enumerate() {
+            if *op != Operand::Reg(Reg::Stack(k)) {
+                self.insts.push(Inst::Copy(Reg::Stack(k), op.clone()));
+            }
+        }
+    }
+
+    /**
+     * Lowers `token`, returning the terminator if it ends the block.
+     */
+    fn step(&mut self, token : &Token, block_of : &dyn Fn(usize) -> usize, next : usize)
+            -> Option<Term> {
+        let labels = &self.def.exec.labels;
+        match token {
+            Token::Pushi(val) => self.stack.push(Operand::Int(*val)),
+            Token::Pushf(val) => self.stack.push(Operand::Float(*val)),
+            Token::Pop => {
+                self.pop();
+            }
+            Token::Load(i) => self.stack.push(Operand::Reg(Reg::Local(*i))),
+            Token::Store(i) => {
+                let val = self.pop();
+                self.materialize(*i);
+                self.insts.push(Inst::Copy(Reg::Local(*i), val));
+            }
+            Token::Stores(i, string) => {
+                self.materialize(*i);
+                self.insts.push(Inst::Copy(Reg::Local(*i), Operand::Str(string.clone())));
+            }
+            Token::Gload(i) => {
+                let t = self.temp();
+                self.insts.push(Inst::Gload(t, *i));
+                self.stack.push(Operand::Reg(t));
+            }
+            Token::Gstore(i) => {
+                let val = self.pop();
+                self.insts.push(Inst::Gstore(*i, val));
+            }
+            Token::Dup => {
+                let top = self.pop();
+                self.stack.push(top.clone());
+                self.stack.push(top);
+            }
+            Token::Call(name) => {
+                let (pops, pushes) = match (self.prog.find(name), self.host.signature(name)) {
+                    (Some(def), _) => (def.par_ts.len(), def.ret_t.arity()),
+                    (None, Some(sig)) => (sig.par_ts.len(), sig.ret_t.arity()),
+                    (None, None) => panic!("callee {} of a verified function not found", name),
+                };
+                let at = self.stack.len() - pops;
+                let args = self.stack.split_off(at);
+                let dst = if pushes == 1 { Some(self.temp()) } else { None };
+                self.insts.push(Inst::Call(dst, name.clone(), args));
+                if let Some(t) = dst {
+                    self.stack.push(Operand::Reg(t));
+                }
+            }
+            Token::Callm(method) => {
+                let def = self.prog.method_defs(method)[0];
+                let at = self.stack.len() - def.par_ts.len();
+                let args = self.stack.split_off(at);
+                let dst = if def.ret_t.arity() == 1 { Some(self.temp()) } else { None };
+                self.insts.push(Inst::Callm(dst, method.clone(), args));
+                if let Some(t) = dst {
+                    self.stack.push(Operand::Reg(t));
+                }
+            }
+            Token::New(class) => {
+                let t = self.temp();
+                self.insts.push(Inst::New(t, class.clone()));
+                self.stack.push(Operand::Reg(t));
+            }
+            Token::Getfield(class, field) => {
+                let obj = self.pop();
+                let t = self.temp();
+                self.insts.push(Inst::Getfield(t, obj, class.clone(), field.clone()));
+                self.stack.push(Operand::Reg(t));
+            }
+            Token::Setfield(class, field) => {
+                let val = self.pop();
+                let obj = self.pop();
+                self.insts.push(Inst::Setfield(obj, class.clone(), field.clone(), val));
+            }
+            Token::Newarr(t) => {
+                let len = self.pop();
+                let dst = self.temp();
+                self.insts.push(Inst::Newarr(dst, t.clone(), len));
+                self.stack.push(Operand::Reg(dst));
+            }
+            Token::Aload => {
+                let i = self.pop();
+                let arr = self.pop();
+                let dst = self.temp();
+                self.insts.push(Inst::Aload(dst, arr, i));
+                self.stack.push(Operand::Reg(dst));
+            }
+            Token::Astore => {
+                let val = self.pop();
+                let i = self.pop();
+                let arr = self.pop();
+                self.insts.push(Inst::Astore(arr, i, val));
+            }
+            Token::Alen => {
+                let arr = self.pop();
+                let dst = self.temp();
+                self.insts.push(Inst::Alen(dst, arr));
+                self.stack.push(Operand::Reg(dst));
+            }
+            Token::Goto(lbl) => {
+                self.spill();
+                return Some(Term::Goto(block_of(labels[lbl])));
+            }
+            Token::Branch(lbl) => {
+                let cond = self.pop();
+                self.spill();
+                return Some(Term::Branch(cond, block_of(labels[lbl]), next));
+            }
+            Token::Ret => {
+                let val = if self.def.ret_t.arity() == 1 { Some(self.pop()) } else { None };
+                return Some(Term::Ret(val));
+            }
+            _ => {
+                if let Some(op) = BinOp::from_token(token) {
+                    let lhs = self.pop();
+                    let rhs = self.pop();
+                    let t = self.temp();
+                    self.insts.push(Inst::Binary(t, op, lhs, rhs));
+                    self.stack.push(Operand::Reg(t));
+                }
+            }
+        }
+        return None;
+    }
+}
+
+/**
+ * What is known about the type of a register while inferring it.
+ */
+#[derive(Debug, Clone, PartialEq)]
+enum Infer {
+    Unset,
+    Known(Type),
+    Any
+}
+
+impl Infer {
+    fn join(&self, other : &Infer) -> Infer {
+        return match (self, other) {
+            (Infer::Unset, x) | (x, Infer::Unset) => x.clone(),
+            (Infer::Known(a), Infer::Known(b)) if a == b => Infer::Known(a.clone()),
+            _ => Infer::Any,
+        };
+    }
+}
+
+/**
+ * Types each register by joining the types of everything written to it.
+ */
+fn infer_types(func : &Function, par_ts : &[Type], prog : &Program, host : &Host) -> HashMap<Reg, Type> {
+    let mut regs : HashMap<Reg, Infer> = HashMap::new();
+    for (r, t) in func.params.iter().zip(par_ts) {
+        regs.insert(*r, Infer::Known(t.clone()));
+    }
+    let operand = |regs : &HashMap<Reg, Infer>, op : &Operand| match op {
+        Operand::Reg(r) => regs.get(r).cloned().unwrap_or(Infer::Unset),
+        Operand::Int(_) => Infer::Known(Type::TInt),
+        Operand::Float(_) => Infer::Known(Type::TFloat),
+        Operand::Str(_) => Infer::Known(Type::TString),
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for inst in func.blocks.iter().flat_map(|b| &b.insts) {
+            let (dst, t) = match inst {
+                Inst::Copy(dst, op) => (*dst, operand(&regs, op)),
+                Inst::Binary(dst, op, lhs, rhs) => {
+                    let t = match (operand(&regs, lhs), operand(&regs, rhs)) {
+                        (Infer::Known(a), Infer::Known(b)) => {
+                            match typeck::binary(&op.token(), &a, &b) {
+                                Ok(t) => Infer::Known(t),
+                                Err(_) => Infer::Any,
+                            }
+                        }
+                        (Infer::Unset, _) | (_, Infer::Unset) => Infer::Unset,
+                        _ => Infer::Any,
+                    };
+                    (*dst, t)
+                }
+                Inst::Call(Some(dst), name, _) => {
+                    let t = match (prog.find(name), host.signature(name)) {
+                        (Some(def), _) => Infer::Known(def.ret_t.clone()),
+                        (None, Some(sig)) => Infer::Known(sig.ret_t.clone()),
+                        (None, None) => Infer::Any,
+                    };
+                    (*dst, t)
+                }
+                Inst::Callm(Some(dst), method, _) => {
+                    let t = prog.method_defs(method).iter()
+                        .map(|d| Infer::Known(d.ret_t.clone()))
+                        .reduce(|a, b| a.join(&b))
+                        .unwrap_or(Infer::Any);
+                    (*dst, t)
+                }
+                Inst::Gload(dst, _) => (*dst, Infer::Any),
+                Inst::Newarr(dst, t, _) => (*dst, Infer::Known(Type::TArray(Box::new(t.clone())))),
+                Inst::Aload(dst, arr, _) => {
+                    let t = match operand(&regs, arr) {
+                        Infer::Known(Type::TArray(elem)) => Infer::Known(*elem),
+                        Infer::Unset => Infer::Unset,
+                        _ => Infer::Any,
+                    };
+                    (*dst, t)
+                }
+                Inst::Alen(dst, _) => (*dst, Infer::Known(Type::TInt)),
+                Inst::New(dst, class) => (*dst, Infer::Known(Type::TClass(class.clone()))),
+                Inst::Getfield(dst, _, class, field) => {
+                    let c = prog.class.find(class).expect("class of a verified program");
+                    let i = prog.class.field(c, field).expect("field of a verified program");
+                    (*dst, Infer::Known(prog.class.fields(c)[i].1.clone()))
+                }
+                _ => continue,
+            };
+            let old = regs.get(&dst).cloned().unwrap_or(Infer::Unset);
+            let new = old.join(&t);
+            if new != old {
+                regs.insert(dst, new);
+                changed = true;
+            }
+        }
+    }
+    return regs.into_iter().filter_map(|(r, t)| match t {
+        Infer::Known(t) => Some((r, t)),
+        _ => None,
+    }).collect();
+}
+
+/**
+ * Lowers a function of a program verified with `host`. Blocks that
+ * cannot be reached are left out.
+ */
+pub fn lower(def : &DeFun, prog : &Program, host : &Host) -> Function {
+    let tokens = &def.exec.tokens;
+    let cfg = Cfg::build(def);
+    let block_of = |pc : usize| cfg.block_of(pc);
+
+    let mut lowering = Lowering {def, prog, host, temps: 0, stack: Vec::new(), insts: Vec::new()};
+    let mut lowered : Vec<Option<Block>> = vec![None; cfg.blocks.len()];
+    let mut work = vec![(0, def.par_ts.len())];
+    while let Some((id, depth)) = work.pop() {
+        if lowered[id].is_some() {
+            continue;
+        }
+        let (start, end) = (cfg.blocks[id].start, cfg.blocks[id].end);
+        lowering.stack = (0..depth).map(|k| Operand::Reg(Reg::Stack(k))).collect();
+        lowering.insts = Vec::new();
+        let mut term = None;
+        for token in &tokens[start..end] {
+            term = lowering.step(token, &block_of, id + 1);
+            if term.is_some() {
+                break;
+            }
+        }
+        let term = match term {
+            Some(t) => t,
+            None if end < tokens.len() => {
+                lowering.spill();
+                Term::Goto(id + 1)
+            }
+            None => {
+                let val = if def.ret_t.arity() == 1 { lowering.stack.pop() } else { None };
+                Term::Ret(val)
+            }
+        };
+        let depth = lowering.stack.len();
+        match &term {
+            Term::Goto(to) => work.push((*to, depth)),
+            Term::Branch(_, to, next) => {
+                work.push((*next, depth));
+                work.push((*to, depth));
+            }
+            Term::Ret(_) => {}
+        }
+        lowered[id] = Some(Block {id, insts: std::mem::take(&mut lowering.insts), term});
+    }
+
+    let mut func = Function {
+        name: def.name.clone(),
+        params: (0..def.par_ts.len()).map(Reg::Stack).collect(),
+        ret_t: def.ret_t.clone(),
+        blocks: lowered.into_iter().flatten().collect(),
+        types: HashMap::new()
+    };
+    func.types = infer_types(&func, &def.par_ts, prog, host);
+    return func;
+}
+
+/**
+ * Lowers every function of a program verified with `host`.
+ */
+pub fn lower_program(prog : &Program, host : &Host) -> Vec<Function> {
+    return prog.func.defs.iter().map(|def| lower(def, prog, host)).collect();
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reg::Local(i) => write!(f, "%l{}", i),
+            Reg::Stack(i) => write!(f, "%s{}", i),
+            Reg::Temp(i) => write!(f, "%t{}", i),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "{}", r),
+            Operand::Int(val) => write!(f, "{}", val),
+            Operand::Float(val) => write!(f, "{:?}", val),
+            Operand::Str(val) => write!(f, "\"{}\"", val),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.token())
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Term::Goto(to) => write!(f, "goto b{}", to),
+            Term::Branch(cond, to, next) => write!(f, "branch {}, b{}, b{}", cond, to, next),
+            Term::Ret(Some(val)) => write!(f, "ret {}", val),
+            Term::Ret(None) => write!(f, "ret"),
+        }
+    }
+}
+
+/**
+ * Prints a function with one instruction per line, blocks introduced by
+ * their `bN:` name.
+ */
+impl fmt::Display for Function {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let params : Vec<String> = self.params.iter().map(|r| self.def(r)).collect();
+        writeln!(f, "function {}({}) -> {} {{", self.name, params.join(", "), self.ret_t)?;
+        for block in &self.blocks {
+            writeln!(f, "b{}:", block.id)?;
+            for inst in &block.insts {
+                write!(f, "    ")?;
+                self.fmt_inst(f, inst)?;
+                writeln!(f)?;
+            }
+            writeln!(f, "    {}", block.term)?;
+        }
+        write!(f, "}}")
+    }
+}
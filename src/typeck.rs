@@ -0,0 +1,357 @@
+/*!
+ * Static type checker over the stack IR.
+ *
+ * Infers the `Type` of every operand stack slot and local variable at
+ * every instruction, and reports operations that would fail at runtime
+ * with a type mismatch. Where paths disagree on the type of a slot it
+ * becomes unknown, and unknown operands are never reported.
+ */
+
+use super::scanner::Token;
+use super::ir::{DeFun, Program};
+use super::mem_alloc::Type;
+use super::error::VerifyError;
+use super::host::Host;
+
+use std::collections::BTreeMap;
+
+/// the inferred type of a slot, `None` if it is unknown
+type Ty = Option<Type>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    stack  : Vec<Ty>,
+    /// the locals of known type, by index
+    locals : BTreeMap<usize, Type>
+}
+
+impl State {
+    /**
+     * Merges the state of another path into this one, telling whether
+     * anything changed.
+     */
+    fn join(&mut self, other : &State) -> bool {
+        let before = self.clone();
+        for (a, b) in self.stack.iter_mut().zip(&other.stack) {
+            if a != b {
+                *a = None;
+            }
+        }
+        self.locals.retain(|i, t| other.locals.get(i) == Some(t));
+        return *self != before;
+    }
+
+    fn pop(&mut self) -> Ty {
+        return self.stack.pop().unwrap_or(None);
+    }
+
+    fn set_local(&mut self, i : usize, t : Ty) {
+        match t {
+            Some(t) => self.locals.insert(i, t),
+            None => self.locals.remove(&i),
+        };
+    }
+}
+
+fn mnemonic(token : &Token) -> String {
+    let text = token.to_string();
+    return text.split(' ').next().unwrap_or("").to_string();
+}
+
+/**
+ * Result type of an arithmetic or comparison instruction, or a
+ * description of why its operands do not fit it.
+ */
+pub fn binary(token : &Token, a : &Type, b : &Type) -> Result<Type, String> {
+    let op = mnemonic(token);
+    let compare = matches!(token,
+        Token::Eq | Token::Ne | Token::Lt | Token::Le | Token::Gt | Token::Ge);
+    return match (a, b) {
+        (Type::TInt, Type::TInt) => Ok(Type::TInt),
+        (Type::TFloat, Type::TFloat) => {
+            match token {
+                Token::Rem => Err(format!("`{}` on float", op)),
+                _ if compare => Ok(Type::TInt),
+                _ => Ok(Type::TFloat),
+            }
+        }
+        (Type::TString, Type::TString) => {
+            match token {
+                Token::Eq => Ok(Type::TInt),
+                _ => Err(format!("`{}` on string", op)),
+            }
+        }
+        (Type::TInt, Type::TFloat) | (Type::TFloat, Type::TInt) => {
+            Err(format!("`{}` mixes int and float", op))
+        }
+        _ if a == b => Err(format!("`{}` on {}", op, a)),
+        _ => Err(format!("`{}` on {} and {}", op, a, b)),
+    };
+}
+
+/**
+ * Applies one instruction to `state`, reporting ill-typed operands to
+ * `report`.
+ */
+fn step(token : &Token, state : &mut State, prog : &Program, host : &Host, report : &mut dyn FnMut(String)) {
+    match token {
+        Token::Pushi(_) => state.stack.push(Some(Type::TInt)),
+        Token::Pushf(_) => state.stack.push(Some(Type::TFloat)),
+        Token::Pop => {
+            state.pop();
+        }
+        Token::Load(i) => {
+            let t = state.locals.get(i).cloned();
+            state.stack.push(t);
+        }
+        Token::Store(i) => {
+            let t = state.pop();
+            state.set_local(*i, t);
+        }
+        Token::Stores(i, _) => state.set_local(*i, Some(Type::TString)),
+        Token::Gload(_) => state.stack.push(None),
+        Token::Gstore(_) => {
+            state.pop();
+        }
+        Token::Dup => {
+            let t = state.pop();
+            state.stack.push(t.clone());
+            state.stack.push(t);
+        }
+        Token::Add | Token::Sub | Token::Mul | Token::Div | Token::Rem |
+        Token::Eq | Token::Ne | Token::Lt | Token::Le | Token::Gt | Token::Ge => {
+            let a = state.pop();
+            let b = state.pop();
+            let t = match (a, b) {
+                (Some(a), Some(b)) => {
+                    match binary(token, &a, &b) {
+                        Ok(t) => Some(t),
+                        Err(msg) => {
+                            report(msg);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            state.stack.push(t);
+        }
+        Token::Branch(_) => {
+            match state.pop() {
+                Some(Type::TInt) | None => {}
+                Some(t) => report(format!("`branch` on {}, expected int", t)),
+            }
+        }
+        Token::New(class) => state.stack.push(Some(Type::TClass(class.clone()))),
+        Token::Getfield(class, field) | Token::Setfield(class, field) => {
+            let val = if let Token::Setfield(..) = token { Some(state.pop()) } else { None };
+            let obj = state.pop();
+            let fields = prog.class.find(class).map(|c| (prog.class.fields(c), prog.class.field(c, field)));
+            let (field, t) = match fields.and_then(|(fields, i)| Some(fields[i?])) {
+                Some((name, t)) => (name, Some(t.clone())),
+                None => (field, None),
+            };
+            let expected = Type::TClass(class.clone());
+            match obj {
+                Some(obj) if ! prog.class.conforms(&obj, &expected) => {
+                    report(format!("`{}` on {}, expected {}", mnemonic(token), obj, class));
+                }
+                _ => {}
+            }
+            match (val, &t) {
+                (None, _) => state.stack.push(t),
+                (Some(Some(v)), Some(t)) if ! prog.class.conforms(&v, t) => {
+                    report(format!("field {} of {} set to {}, expected {}", field, class, v, t));
+                }
+                _ => {}
+            }
+        }
+        Token::Call(name) => {
+            let (par_ts, ret_t) = match (prog.find(name), host.signature(name)) {
+                (Some(def), _) => (def.par_ts.iter().cloned().map(Some).collect(), &def.ret_t),
+                (None, Some(sig)) => (sig.par_ts.clone(), &sig.ret_t),
+                (None, None) => return,
+            };
+            let at = state.stack.len().saturating_sub(par_ts.len());
+            let args = state.stack.split_off(at);
+            check_args(name, &par_ts, &args, prog, report);
+            if ret_t.arity() == 1 {
+                state.stack.push(Some(ret_t.clone()));
+            }
+        }
+        Token::Newarr(t) => {
+            let len = state.pop();
+            check_int(token, &len, report);
+            state.stack.push(Some(Type::TArray(Box::new(t.clone()))));
+        }
+        Token::Aload | Token::Astore | Token::Alen => {
+            let val = if let Token::Astore = token { Some(state.pop()) } else { None };
+            if ! matches!(token, Token::Alen) {
+                let i = state.pop();
+                check_int(token, &i, report);
+            }
+            let elem = match state.pop() {
+                Some(Type::TArray(elem)) => Some(*elem),
+                Some(t) => {
+                    report(format!("`{}` on {}, expected an array", mnemonic(token), t));
+                    None
+                }
+                None => None,
+            };
+            match (token, val, elem) {
+                (Token::Aload, _, elem) => state.stack.push(elem),
+                (Token::Alen, _, _) => state.stack.push(Some(Type::TInt)),
+                (_, Some(Some(v)), Some(elem)) if ! prog.class.conforms(&v, &elem) => {
+                    report(format!("element of {}[] set to {}", elem, v));
+                }
+                _ => {}
+            }
+        }
+        Token::Callm(method) => {
+            let defs = prog.method_defs(method);
+            let first = match defs.first() {
+                Some(d) => d,
+                None => return,
+            };
+            let at = state.stack.len().saturating_sub(first.par_ts.len());
+            let args = state.stack.split_off(at);
+            // with the class of the object known, so is the function that runs
+            let def = match args.first() {
+                Some(Some(Type::TClass(class))) => {
+                    let found = prog.class.find(class)
+                        .and_then(|c| prog.class.method(c, method))
+                        .and_then(|name| prog.find(&name));
+                    if found.is_none() {
+                        report(format!("class {} has no method {}", class, method));
+                    }
+                    found
+                }
+                Some(Some(t)) => {
+                    report(format!("`callm {}` on {}, expected an object", method, t));
+                    None
+                }
+                _ => None,
+            };
+            if let Some(def) = def {
+                let par_ts : Vec<Ty> = def.par_ts.iter().cloned().map(Some).collect();
+                check_args(&def.name, &par_ts, &args, prog, report);
+            }
+            if first.ret_t.arity() == 1 {
+                let ret_t = def.map(|d| d.ret_t.clone())
+                    .or_else(|| Some(first.ret_t.clone()).filter(|t| defs.iter().all(|d| d.ret_t == *t)));
+                state.stack.push(ret_t);
+            }
+        }
+        _ => {}
+    }
+}
+
+/**
+ * Reports a length or index operand of `token` that is not an int.
+ */
+fn check_int(token : &Token, t : &Ty, report : &mut dyn FnMut(String)) {
+    match t {
+        Some(Type::TInt) | None => {}
+        Some(t) => report(format!("`{}` with {} operand, expected int", mnemonic(token), t)),
+    }
+}
+
+/**
+ * Reports the arguments of a call to function `name` whose type does not
+ * fit its parameters. A parameter of unknown type takes any argument.
+ */
+fn check_args(name : &str, par_ts : &[Ty], args : &[Ty], prog : &Program,
+        report : &mut dyn FnMut(String)) {
+    for (i, (arg, t)) in args.iter().zip(par_ts).enumerate() {
+        if let (Some(arg), Some(t)) = (arg, t) {
+            if ! prog.class.conforms(arg, t) {
+                report(format!("argument {} of {}() is {}, expected {}", i, name, arg, t));
+            }
+        }
+    }
+}
+
+fn check_return(def : &DeFun, state : &State, prog : &Program, report : &mut dyn FnMut(String)) {
+    if let Some(Some(t)) = state.stack.last() {
+        if def.ret_t.arity() == 1 && ! prog.class.conforms(t, &def.ret_t) {
+            report(format!("returns {}, expected {}", t, def.ret_t));
+        }
+    }
+}
+
+fn check_defun(def : &DeFun, prog : &Program, host : &Host, errors : &mut Vec<VerifyError>) {
+    let exec = &def.exec;
+    let entry = State {
+        stack: def.par_ts.iter().cloned().map(Some).collect(),
+        locals: BTreeMap::new()
+    };
+
+    // infer the state before each instruction, and at the end
+    let mut states : Vec<Option<State>> = vec![None; exec.tokens.len() + 1];
+    let mut work = vec![(0, entry)];
+    let mut ignore = |_ : String| {};
+    while let Some((pc, state)) = work.pop() {
+        match &mut states[pc] {
+            Some(known) => {
+                if ! known.join(&state) {
+                    continue;
+                }
+            }
+            slot => *slot = Some(state),
+        }
+        if pc == exec.tokens.len() {
+            continue;
+        }
+        let mut next = states[pc].clone().unwrap();
+        let token = &exec.tokens[pc];
+        step(token, &mut next, prog, host, &mut ignore);
+        match token {
+            Token::Goto(lbl) => work.push((exec.labels[lbl], next)),
+            Token::Branch(lbl) => {
+                work.push((exec.labels[lbl], next.clone()));
+                work.push((pc + 1, next));
+            }
+            Token::Ret => {}
+            _ => work.push((pc + 1, next)),
+        }
+    }
+
+    // report once per instruction, from the final states
+    for (pc, state) in states.iter().enumerate() {
+        let state = match state {
+            Some(s) => s,
+            None => continue,
+        };
+        let row = match exec.rows.get(pc) {
+            Some(row) => *row,
+            None => exec.rows.last().cloned().unwrap_or(0),
+        };
+        let mut report = |message : String| {
+            errors.push(VerifyError {function: def.name.clone(), row: Some(row), message});
+        };
+        if pc == exec.tokens.len() {
+            check_return(def, state, prog, &mut report);
+            continue;
+        }
+        let token = &exec.tokens[pc];
+        let mut next = state.clone();
+        step(token, &mut next, prog, host, &mut report);
+        if let Token::Ret = token {
+            check_return(def, &next, prog, &mut report);
+        }
+    }
+}
+
+/**
+ * Type-checks every function of a program verified with `host`.
+ */
+pub fn check(prog : &Program, host : &Host) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+    for def in &prog.func.defs {
+        check_defun(def, prog, host, &mut errors);
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    return Err(errors);
+}
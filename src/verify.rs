@@ -0,0 +1,315 @@
+/*!
+ * Static checks run on a program before it is executed.
+ */
+
+use super::scanner::Token;
+use super::ir::{DeFun, Program};
+use super::mem_alloc::Type;
+use super::error::VerifyError;
+use super::host::Host;
+
+use std::collections::HashSet;
+
+/// the locals a function can have, as many as a `u16` address names
+pub const MAX_LOCALS : usize = 1 << 16;
+/// the globals a program can have, as many as a `u16` address names
+pub const MAX_GLOBALS : usize = 1 << 16;
+
+/**
+ * Number of values an instruction pops and pushes, or `None` if it can
+ * never be executed.
+ */
+fn stack_effect(token : &Token, prog : &Program, host : &Host) -> Option<(usize, usize)> {
+    return match token {
+        Token::Pushi(_) | Token::Pushf(_) => Some((0, 1)),
+        Token::Load(_) | Token::Gload(_) => Some((0, 1)),
+        Token::Store(_) | Token::Gstore(_) | Token::Pop => Some((1, 0)),
+        Token::Stores(_, _) => Some((0, 0)),
+        Token::Dup => Some((1, 2)),
+        Token::Add | Token::Sub | Token::Mul | Token::Div | Token::Rem |
+        Token::Eq | Token::Ne | Token::Lt | Token::Le | Token::Gt | Token::Ge => Some((2, 1)),
+        Token::Label(_) | Token::Goto(_) => Some((0, 0)),
+        Token::Branch(_) => Some((1, 0)),
+        Token::Ret => Some((0, 0)),
+        Token::New(class) => prog.class.find(class).map(|_| (0, 1)),
+        Token::Getfield(class, field) | Token::Setfield(class, field) => {
+            prog.class.field(prog.class.find(class)?, field)?;
+            if let Token::Getfield(..) = token { Some((1, 1)) } else { Some((2, 0)) }
+        }
+        Token::Newarr(t) => if bad_type(t, prog).is_none() { Some((1, 1)) } else { None },
+        Token::Aload => Some((2, 1)),
+        Token::Astore => Some((3, 0)),
+        Token::Alen => Some((1, 1)),
+        Token::Callm(method) => {
+            prog.method_defs(method).first().map(|d| (d.par_ts.len(), d.ret_t.arity()))
+        }
+        Token::Call(name) => {
+            match prog.find(name) {
+                Some(def) => Some((def.par_ts.len(), def.ret_t.arity())),
+                None => host.signature(name).map(|sig| (sig.par_ts.len(), sig.ret_t.arity())),
+            }
+        }
+        _ => None,
+    };
+}
+
+/**
+ * Tells why a field or array element cannot be of type `t`, if it cannot.
+ */
+fn bad_type(t : &Type, prog : &Program) -> Option<String> {
+    return match t {
+        Type::Void => Some("cannot hold NULL".to_string()),
+        Type::TClass(class) if prog.class.find(class).is_none() => {
+            Some(format!("has unknown class {}", class))
+        }
+        Type::TArray(elem) => bad_type(elem, prog),
+        _ => None,
+    };
+}
+
+/**
+ * Checks one function: its jumps, its calls and, by abstract
+ * interpretation, the depth of its operand stack.
+ */
+fn verify_defun(def : &DeFun, prog : &Program, host : &Host, errors : &mut Vec<VerifyError>) {
+    let exec = &def.exec;
+    let err = |pc : usize, message : String| {
+        VerifyError {function: def.name.clone(), row: exec.rows.get(pc).cloned(), message}
+    };
+
+    let mut seen = HashSet::new();
+    for (pc, token) in exec.tokens.iter().enumerate() {
+        match token {
+            Token::Label(lbl) => {
+                if ! seen.insert(*lbl) {
+                    errors.push(err(pc, format!("label {} is defined twice", lbl)));
+                }
+            }
+            Token::Goto(lbl) | Token::Branch(lbl) => {
+                if ! exec.labels.contains_key(lbl) {
+                    errors.push(err(pc, format!("label {} not found", lbl)));
+                }
+            }
+            Token::Load(i) | Token::Store(i) | Token::Stores(i, _) if *i >= MAX_LOCALS => {
+                errors.push(err(pc, format!("local {} is beyond the {} locals of a function", i, MAX_LOCALS)));
+            }
+            Token::Gload(i) | Token::Gstore(i) if *i >= MAX_GLOBALS => {
+                errors.push(err(pc, format!("global {} is beyond the {} globals of a program", i, MAX_GLOBALS)));
+            }
+            Token::Call(name) => {
+                if stack_effect(token, prog, host).is_none() {
+                    errors.push(err(pc, format!("{}() not found", name)));
+                }
+            }
+            Token::Newarr(t) => {
+                if let Some(why) = bad_type(t, prog) {
+                    errors.push(err(pc, format!("array {}", why)));
+                }
+            }
+            Token::Callm(method) => {
+                if stack_effect(token, prog, host).is_none() {
+                    errors.push(err(pc, format!("method {} not found", method)));
+                }
+            }
+            Token::New(class) | Token::Getfield(class, _) | Token::Setfield(class, _) => {
+                if stack_effect(token, prog, host).is_none() {
+                    let message = match token {
+                        Token::Getfield(_, field) | Token::Setfield(_, field)
+                                if prog.class.find(class).is_some() => {
+                            format!("class {} has no field {}", class, field)
+                        }
+                        _ => format!("class {} not found", class),
+                    };
+                    errors.push(err(pc, message));
+                }
+            }
+            _ => {
+                if stack_effect(token, prog, host).is_none() {
+                    errors.push(err(pc, format!("`{}` cannot be executed", token)));
+                }
+            }
+        }
+    }
+    if ! errors.is_empty() {
+        return;
+    }
+
+    // depth of the stack before each instruction, and at the end
+    let mut depth : Vec<Option<usize>> = vec![None; exec.tokens.len() + 1];
+    let mut work = vec![(0, def.par_ts.len())];
+    while let Some((pc, d)) = work.pop() {
+        match depth[pc] {
+            Some(known) => {
+                if known != d {
+                    errors.push(err(pc, format!(
+                        "stack depth {} here disagrees with depth {} from another path", d, known
+                    )));
+                }
+                continue;
+            }
+            None => depth[pc] = Some(d),
+        }
+        if pc == exec.tokens.len() {
+            if d != def.ret_t.arity() {
+                errors.push(err(pc.saturating_sub(1), format!(
+                    "reaches endef with {} values, expected {}", d, def.ret_t.arity()
+                )));
+            }
+            continue;
+        }
+
+        let token = &exec.tokens[pc];
+        let (pops, pushes) = stack_effect(token, prog, host).unwrap();
+        if d < pops {
+            errors.push(err(pc, format!("`{}` pops {} values from a stack of {}", token, pops, d)));
+            continue;
+        }
+        let d = d - pops + pushes;
+        match token {
+            Token::Goto(lbl) => work.push((exec.labels[lbl], d)),
+            Token::Branch(lbl) => {
+                work.push((exec.labels[lbl], d));
+                work.push((pc + 1, d));
+            }
+            Token::Ret => {
+                if d != def.ret_t.arity() {
+                    errors.push(err(pc, format!(
+                        "returns {} values, expected {}", d, def.ret_t.arity()
+                    )));
+                }
+            }
+            _ => work.push((pc + 1, d)),
+        }
+    }
+}
+
+/**
+ * Checks the declarations of the `.class` section: names are unique, the
+ * parents exist without forming a cycle, every field has a type an object
+ * can hold, and every method has a function that overrides consistently.
+ */
+fn verify_classes(prog : &Program, errors : &mut Vec<VerifyError>) {
+    let mut names = HashSet::new();
+    for (c, decl) in prog.class.defs.iter().enumerate() {
+        let mut err = |message : String| {
+            errors.push(VerifyError {function: decl.name.clone(), row: None, message});
+        };
+        if ! names.insert(decl.name.as_str()) {
+            err("class is defined twice".to_string());
+        }
+        let ancestors = prog.class.ancestors(c);
+        if let Some(parent) = &decl.parent {
+            if prog.class.find(parent).is_none() {
+                err(format!("extends unknown class {}", parent));
+            }
+        }
+        let last = &prog.class.defs[*ancestors.last().unwrap()];
+        if last.parent.as_ref().is_some_and(|p| prog.class.find(p).is_some()) {
+            err("class inherits from itself".to_string());
+            continue;
+        }
+
+        let mut fields = HashSet::new();
+        for (name, _) in prog.class.fields(c) {
+            if ! fields.insert(name.as_str()) {
+                err(format!("field {} is defined twice", name));
+            }
+        }
+        for (name, t) in &decl.fields {
+            if let Some(why) = bad_type(t, prog) {
+                err(format!("field {} {}", name, why));
+            }
+        }
+
+        let mut methods = HashSet::new();
+        for method in &decl.methods {
+            if ! methods.insert(method.as_str()) {
+                err(format!("method {} is declared twice", method));
+            }
+            let name = format!("{}.{}", decl.name, method);
+            let def = match prog.find(&name) {
+                Some(def) => def,
+                None => {
+                    err(format!("method {} has no function {}", method, name));
+                    continue;
+                }
+            };
+            if def.par_ts.first() != Some(&Type::TClass(decl.name.clone())) {
+                err(format!("{} must take a {} as its first parameter", name, decl.name));
+            }
+            let overridden = ancestors.iter().skip(1)
+                .find_map(|a| prog.class.method(*a, method))
+                .and_then(|name| prog.find(&name));
+            if let Some(base) = overridden {
+                if def.par_ts.get(1..) != base.par_ts.get(1..) || def.ret_t != base.ret_t {
+                    err(format!("{} does not have the signature of {}", name, base.name));
+                }
+            }
+        }
+    }
+
+    // `callm` only names the method, so its stack effect must not depend on the class
+    let mut seen = HashSet::new();
+    for decl in &prog.class.defs {
+        for method in &decl.methods {
+            if ! seen.insert(method.as_str()) {
+                continue;
+            }
+            let defs = prog.method_defs(method);
+            let sig = |d : &DeFun| (d.par_ts.len(), d.ret_t.arity());
+            if let Some(other) = defs.iter().find(|d| sig(d) != sig(defs[0])) {
+                errors.push(VerifyError {
+                    function: other.name.clone(), row: None,
+                    message: format!("method {} takes or returns other values than {}", method, defs[0].name)
+                });
+            }
+        }
+    }
+}
+
+/**
+ * Checks a program before it runs, so that malformed control flow, calls
+ * and stack usage are reported up front instead of failing midway. Calls
+ * may name the functions of `host` besides those of `prog`.
+ */
+pub fn verify(prog : &Program, host : &Host) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+    verify_classes(prog, &mut errors);
+    let mut names = HashSet::new();
+    for def in &prog.func.defs {
+        if ! names.insert(def.name.as_str()) {
+            errors.push(VerifyError {
+                function: def.name.clone(), row: None,
+                message: "function is defined twice".to_string()
+            });
+        }
+        if def.name == "main" && ! def.par_ts.is_empty() {
+            errors.push(VerifyError {
+                function: def.name.clone(), row: None,
+                message: "main must not take parameters".to_string()
+            });
+        }
+        if host.find(&def.name).is_some() {
+            errors.push(VerifyError {
+                function: def.name.clone(), row: None,
+                message: "function has the name of a host function".to_string()
+            });
+        }
+    }
+    if ! names.contains("main") {
+        errors.push(VerifyError {
+            function: "main".to_string(), row: None,
+            message: "function not found".to_string()
+        });
+    }
+    for def in &prog.func.defs {
+        let mut found = Vec::new();
+        verify_defun(def, prog, host, &mut found);
+        errors.append(&mut found);
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+    return Err(errors);
+}
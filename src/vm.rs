@@ -0,0 +1,844 @@
+/*!
+ * The execution core.
+ *
+ * A `Program` is linked into an `Image` once before it runs: calls are
+ * resolved to function indices and labels to instruction offsets, and
+ * every class gets a vtable of the functions its methods run. A
+ * `Machine` then runs the image on a contiguous operand stack with an
+ * explicit stack of call frames, so calls never recurse on the Rust
+ * stack and never copy the program.
+ */
+
+use super::scanner::Token;
+use super::ir::{DeFun, Program};
+use super::mem_alloc::{Array, Atom, Frame, Memory, Object, Type};
+use super::error::{Fault, TraceFrame, VmError};
+use super::host::{Context, Host};
+use super::runtime::Console;
+use super::jit::Jit;
+use super::gc;
+use super::opt::Profile;
+use super::verify::{MAX_GLOBALS, MAX_LOCALS};
+
+use std::collections::HashMap;
+
+/**
+ * An instruction with its operands resolved.
+ *
+ * `Label`s become `Nop`s so that instruction indices, and with them
+ * `DeFun::exec.rows`, stay the same as in the `Program`. A `call` in
+ * tail position becomes a `TailCall`, which reuses the frame of the
+ * caller.
+ */
+#[derive(Debug, Clone)]
+pub enum Op {
+    Pushi(i32), Pushf(f32), Pop,
+    Load(usize), Store(usize), Stores(usize, String),
+    Gload(usize), Gstore(usize), Dup,
+    Add, Sub, Mul, Div, Rem,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    Goto(usize), Branch(usize),
+    Call(usize), TailCall(usize),
+    /// a call of a host function, by index into the `Host` the image is
+    /// linked with
+    Host(usize),
+    /// operands are indices into `Image.classes` and their fields
+    New(usize), Getfield(usize, usize), Setfield(usize, usize),
+    /// a method call, by index into `Image.methods` and argument count
+    /// including the object it is called on
+    Callm(usize, usize),
+    Newarr(Type), Aload, Astore, Alen,
+    Ret, Nop
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name    : String,
+    pub par_ts  : Vec<Type>,
+    pub ret_t   : Type,
+    pub code    : Vec<Op>,
+    pub rows    : Vec<usize>,
+    /// number of local variable slots the code uses
+    pub nlocals : usize
+}
+
+#[derive(Debug, Clone)]
+pub struct Class {
+    pub name    : String,
+    pub parent  : Option<usize>,
+    /// all fields of an object, the inherited ones first
+    pub fields  : Vec<(String, Type)>,
+    /// the function each method of `Image.methods` runs, if the class has it
+    pub vtable  : Vec<Option<usize>>
+}
+
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub funcs     : Vec<Function>,
+    pub classes   : Vec<Class>,
+    /// names of the methods of all classes
+    pub methods   : Vec<String>,
+    pub class_ids : HashMap<String, usize>
+}
+
+impl Image {
+    /**
+     * Resolves every call, jump and field access of `prog`. Calls of
+     * functions `prog` does not define resolve to those of `host`.
+     */
+    pub fn link(prog : &Program, host : &Host) -> Result<Image, VmError> {
+        let mut methods : Vec<String> = Vec::new();
+        for decl in &prog.class.defs {
+            for method in &decl.methods {
+                if ! methods.contains(method) {
+                    methods.push(method.clone());
+                }
+            }
+        }
+        let mut funcs = Vec::new();
+        for def in &prog.func.defs {
+            funcs.push(Image::link_defun(def, prog, host, &methods)?);
+        }
+        let mut classes = Vec::new();
+        let mut class_ids = HashMap::new();
+        for (c, decl) in prog.class.defs.iter().enumerate() {
+            let vtable = methods.iter().map(|m| {
+                let name = prog.class.method(c, m)?;
+                prog.func.defs.iter().position(|d| d.name == name)
+            }).collect();
+            classes.push(Class {
+                name: decl.name.clone(),
+                parent: decl.parent.as_ref().and_then(|p| prog.class.find(p)),
+                fields: prog.class.fields(c).into_iter().cloned().collect(),
+                vtable
+            });
+            class_ids.insert(decl.name.clone(), c);
+        }
+        return Ok(Image {funcs, classes, methods, class_ids});
+    }
+
+    fn link_defun(def : &DeFun, prog : &Program, host : &Host, methods : &[String])
+            -> Result<Function, VmError> {
+        let exec = &def.exec;
+        let mut code = Vec::new();
+        let mut nlocals = 0;
+        for (pc, token) in exec.tokens.iter().enumerate() {
+            let at = |fault : Fault| VmError::new(fault, &def.name, pc, exec.rows[pc]);
+            let label = |lbl : &usize| match exec.labels.get(lbl) {
+                Some(target) => Ok(*target),
+                None => Err(at(Fault::LabelNotFound(*lbl))),
+            };
+            let class_of = |name : &String| match prog.class.find(name) {
+                Some(c) => Ok(c),
+                None => Err(at(Fault::UnknownClass(name.clone()))),
+            };
+            let field_of = |c : usize, field : &String| match prog.class.field(c, field) {
+                Some(i) => Ok(i),
+                None => Err(at(Fault::UnknownField(prog.class.defs[c].name.clone(), field.clone()))),
+            };
+            // the number of locals that local `i` needs
+            let local = |i : &usize| match i.checked_add(1) {
+                Some(n) if n <= MAX_LOCALS => Ok(n),
+                _ => Err(at(Fault::AddressOutOfRange(*i))),
+            };
+            let global = |i : &usize| {
+                if *i < MAX_GLOBALS { Ok(*i) } else { Err(at(Fault::AddressOutOfRange(*i))) }
+            };
+            let op = match token {
+                Token::Pushi(val) => Op::Pushi(*val),
+                Token::Pushf(val) => Op::Pushf(*val),
+                Token::Pop => Op::Pop,
+                Token::Load(i) => {
+                    nlocals = nlocals.max(local(i)?);
+                    Op::Load(*i)
+                }
+                Token::Store(i) => {
+                    nlocals = nlocals.max(local(i)?);
+                    Op::Store(*i)
+                }
+                Token::Stores(i, string) => {
+                    nlocals = nlocals.max(local(i)?);
+                    Op::Stores(*i, string.clone())
+                }
+                Token::Gload(i) => Op::Gload(global(i)?),
+                Token::Gstore(i) => Op::Gstore(global(i)?),
+                Token::Dup => Op::Dup,
+                Token::Add => Op::Add,
+                Token::Sub => Op::Sub,
+                Token::Mul => Op::Mul,
+                Token::Div => Op::Div,
+                Token::Rem => Op::Rem,
+                Token::Eq => Op::Eq,
+                Token::Ne => Op::Ne,
+                Token::Lt => Op::Lt,
+                Token::Le => Op::Le,
+                Token::Gt => Op::Gt,
+                Token::Ge => Op::Ge,
+                Token::Label(_) => Op::Nop,
+                Token::Goto(lbl) => Op::Goto(label(lbl)?),
+                Token::Branch(lbl) => Op::Branch(label(lbl)?),
+                Token::Ret => Op::Ret,
+                Token::New(class) => Op::New(class_of(class)?),
+                Token::Getfield(class, field) => {
+                    let c = class_of(class)?;
+                    Op::Getfield(c, field_of(c, field)?)
+                }
+                Token::Setfield(class, field) => {
+                    let c = class_of(class)?;
+                    Op::Setfield(c, field_of(c, field)?)
+                }
+                Token::Newarr(t) => Op::Newarr(t.clone()),
+                Token::Aload => Op::Aload,
+                Token::Astore => Op::Astore,
+                Token::Alen => Op::Alen,
+                Token::Callm(method) => {
+                    let m = match methods.iter().position(|m| m == method) {
+                        Some(m) => m,
+                        None => return Err(at(Fault::UnknownMethod(method.clone()))),
+                    };
+                    let nargs = prog.method_defs(method).first().map_or(0, |d| d.par_ts.len());
+                    Op::Callm(m, nargs)
+                }
+                Token::Call(name) => {
+                    match prog.func.defs.iter().position(|d| d.name == *name) {
+                        Some(i) if prog.func.defs[i].ret_t == def.ret_t && in_tail_position(def, pc) => {
+                            Op::TailCall(i)
+                        }
+                        Some(i) => Op::Call(i),
+                        None => match host.find(name) {
+                            Some(i) => Op::Host(i),
+                            None => return Err(at(Fault::UnknownFunction(name.clone()))),
+                        },
+                    }
+                }
+                t => return Err(at(Fault::Unsupported(t.to_string()))),
+            };
+            code.push(op);
+        }
+        return Ok(Function {
+            name: def.name.clone(),
+            par_ts: def.par_ts.clone(),
+            ret_t: def.ret_t.clone(),
+            code,
+            rows: exec.rows.clone(),
+            nlocals
+        });
+    }
+
+    pub fn find(&self, name : &str) -> Option<usize> {
+        return self.funcs.iter().position(|f| f.name == name);
+    }
+
+    /**
+     * Whether class `c` is class `of` or inherits from it.
+     */
+    pub fn inherits(&self, c : usize, of : usize) -> bool {
+        let mut c = Some(c);
+        while let Some(id) = c {
+            if id == of {
+                return true;
+            }
+            c = self.classes[id].parent;
+        }
+        return false;
+    }
+
+    /**
+     * Whether `val` is a value of type `t`. A reference must refer to an
+     * object of class `t` or of a class inheriting from it, or to an
+     * array of exactly the element type of `t`.
+     */
+    pub fn conforms(&self, heap : &[Atom], val : &Atom, t : &Type) -> bool {
+        return match (val, t) {
+            (Atom::Ref(r), Type::TClass(name)) => match heap.get(*r) {
+                Some(Atom::Object(obj)) => match (self.class_ids.get(&obj.class), self.class_ids.get(name)) {
+                    (Some(found), Some(c)) => self.inherits(*found, *c),
+                    _ => false,
+                },
+                _ => false,
+            },
+            (Atom::Ref(r), Type::TArray(elem)) => {
+                matches!(heap.get(*r), Some(Atom::Array(arr)) if arr.elem == **elem)
+            }
+            _ => val.has_type(t),
+        };
+    }
+}
+
+/**
+ * Whether the instruction at `pc` is followed by nothing but labels and
+ * `goto`s before the function returns.
+ */
+fn in_tail_position(def : &DeFun, pc : usize) -> bool {
+    let exec = &def.exec;
+    let mut seen = vec![false; exec.tokens.len()];
+    let mut pc = pc + 1;
+    loop {
+        match exec.tokens.get(pc) {
+            None | Some(Token::Ret) => return true,
+            Some(Token::Label(_)) => pc += 1,
+            Some(Token::Goto(lbl)) => {
+                if seen[pc] {
+                    return false;
+                }
+                seen[pc] = true;
+                match exec.labels.get(lbl) {
+                    Some(target) => pc = *target,
+                    None => return false,
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/**
+ * Why the dispatch loop handed control back to `Machine::run`.
+ */
+enum Flow {
+    Call(usize),
+    TailCall(usize),
+    Return,
+    /// the heap is to be collected, making room for allocating this many
+    /// cells, before the last instruction runs again
+    Collect(usize),
+    /// single-stepping ran one instruction
+    Step
+}
+
+fn pop(stack : &mut Vec<Atom>, base : usize) -> Result<Atom, Fault> {
+    if stack.len() <= base {
+        return Err(Fault::StackUnderflow);
+    }
+    return Ok(stack.pop().unwrap());
+}
+
+/**
+ * The class of the object `obj` refers to.
+ */
+fn class_of(image : &Image, heap : &[Atom], obj : &Atom) -> Result<usize, Fault> {
+    let found = match obj {
+        Atom::Ref(r) => heap.get(*r),
+        _ => None,
+    };
+    return match found {
+        Some(Atom::Object(found)) => Ok(image.class_ids[&found.class]),
+        _ => Err(Fault::NotAnObject(obj.clone())),
+    };
+}
+
+/**
+ * The fields of the object `obj` refers to, which must be of class `c`
+ * or inherit from it.
+ */
+fn fields<'h>(image : &Image, heap : &'h mut [Atom], obj : &Atom, c : usize)
+        -> Result<&'h mut Vec<Atom>, Fault> {
+    let found = class_of(image, heap, obj)?;
+    if ! image.inherits(found, c) {
+        let (expected, found) = (&image.classes[c].name, &image.classes[found].name);
+        return Err(Fault::ClassMismatch(expected.clone(), found.clone()));
+    }
+    return match obj {
+        Atom::Ref(r) => match &mut heap[*r] {
+            Atom::Object(obj) => Ok(&mut obj.fields),
+            _ => unreachable!("class_of found an object"),
+        },
+        _ => unreachable!("class_of found a reference"),
+    };
+}
+
+/**
+ * The array `arr` refers to.
+ */
+fn array<'h>(heap : &'h mut [Atom], arr : &Atom) -> Result<&'h mut Array, Fault> {
+    let found = match arr {
+        Atom::Ref(r) => heap.get_mut(*r),
+        _ => None,
+    };
+    return match found {
+        Some(Atom::Array(found)) => Ok(found),
+        _ => Err(Fault::NotAnArray(arr.clone())),
+    };
+}
+
+/**
+ * Checks that `index` is an int within the bounds of `arr`.
+ */
+fn index(arr : &Array, index : Atom) -> Result<usize, Fault> {
+    return match index {
+        Atom::VInt(i) if i >= 0 && (i as usize) < arr.items.len() => Ok(i as usize),
+        Atom::VInt(i) => Err(Fault::IndexOutOfBounds(i, arr.items.len())),
+        v => Err(Fault::TypeMismatch("index", v, Atom::VInt(0))),
+    };
+}
+
+/**
+ * Runs `code` from `*pc` on the innermost frame of `mem` until it calls,
+ * returns or faults, or with `STEP` for one instruction at most. `*pc` is
+ * left after the last instruction executed.
+ */
+fn dispatch<const STEP : bool>(image : &Image, host : &Host, io : &mut Console, code : &[Op],
+        pc : &mut usize, base : usize, mem : &mut Memory) -> Result<Flow, Fault> {
+    let Memory {stack, frames, globals, heap, free, gc} = mem;
+    let locals = &mut frames.last_mut().unwrap().locals;
+    while *pc < code.len() {
+        let op = &code[*pc];
+        *pc += 1;
+        match op {
+            Op::Pushi(val) => stack.push(Atom::VInt(*val)),
+            Op::Pushf(val) => stack.push(Atom::VFloat(*val)),
+            Op::Pop => {
+                pop(stack, base)?;
+            }
+            Op::Load(i) => {
+                match Memory::get_slot(locals, *i) {
+                    Some(val) => stack.push(val),
+                    None => return Err(Fault::UnsetVariable(*i)),
+                }
+            }
+            Op::Store(i) => locals[*i] = pop(stack, base)?,
+            Op::Stores(i, string) => locals[*i] = Atom::VString(string.clone()),
+            Op::Gload(i) => {
+                match Memory::get_slot(globals, *i) {
+                    Some(val) => stack.push(val),
+                    None => return Err(Fault::UnsetGlobal(*i)),
+                }
+            }
+            Op::Gstore(i) => {
+                let val = pop(stack, base)?;
+                Memory::set_slot(globals, *i, val);
+            }
+            Op::Dup => {
+                let top = pop(stack, base)?;
+                stack.push(top.clone());
+                stack.push(top);
+            }
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Rem |
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                let a = pop(stack, base)?;
+                let b = pop(stack, base)?;
+                let val = match op {
+                    Op::Add => a.plus(b),
+                    Op::Sub => a.minus(b),
+                    Op::Mul => a.mult(b),
+                    Op::Div => a.div(b),
+                    Op::Rem => a.rem(b),
+                    Op::Eq => a.eq(b),
+                    Op::Ne => a.ne(b),
+                    Op::Lt => a.lt(b),
+                    Op::Le => a.le(b),
+                    Op::Gt => a.gt(b),
+                    _ => a.ge(b),
+                }?;
+                stack.push(val);
+            }
+            Op::Goto(target) => *pc = *target,
+            Op::Branch(target) => {
+                match pop(stack, base)? {
+                    Atom::VInt(i) => {
+                        if i != 0 {
+                            *pc = *target;
+                        }
+                    }
+                    z => return Err(Fault::TypeMismatch("branch", z, Atom::VInt(0))),
+                }
+            }
+            Op::Host(i) => {
+                let sig = &host.funcs[*i].sig;
+                let nargs = sig.par_ts.len();
+                if stack.len() - base < nargs {
+                    return Err(Fault::ArgumentCount(nargs, stack.len() - base));
+                }
+                let args = stack.split_off(stack.len() - nargs);
+                for (k, (arg, t)) in args.iter().zip(&sig.par_ts).enumerate() {
+                    match t {
+                        Some(t) if ! image.conforms(heap, arg, t) => {
+                            return Err(Fault::ArgumentType(k, t.clone(), arg.clone()));
+                        }
+                        _ => {}
+                    }
+                }
+                let val = host.call(*i, &args, &mut Context {heap, free, gc, io, image})?;
+                if sig.ret_t.arity() == 1 {
+                    if ! image.conforms(heap, &val, &sig.ret_t) {
+                        return Err(Fault::ReturnType(sig.ret_t.clone(), val));
+                    }
+                    stack.push(val);
+                }
+            }
+            Op::New(_) if gc.due(1) => {
+                *pc -= 1;
+                return Ok(Flow::Collect(1));
+            }
+            Op::New(c) => {
+                let class = &image.classes[*c];
+                let obj = Atom::Object(Box::new(Object {
+                    class: class.name.clone(),
+                    fields: vec![Atom::Null; class.fields.len()]
+                }));
+                stack.push(Atom::Ref(Memory::alloc(heap, free, gc, obj)));
+            }
+            Op::Getfield(c, i) => {
+                let obj = pop(stack, base)?;
+                let class = &image.classes[*c];
+                match Memory::get_slot(fields(image, heap, &obj, *c)?, *i) {
+                    Some(val) => stack.push(val),
+                    None => return Err(Fault::UnsetField(class.name.clone(), class.fields[*i].0.clone())),
+                }
+            }
+            Op::Setfield(c, i) => {
+                let val = pop(stack, base)?;
+                let obj = pop(stack, base)?;
+                let (name, t) = &image.classes[*c].fields[*i];
+                if ! image.conforms(heap, &val, t) {
+                    return Err(Fault::FieldType(name.clone(), t.clone(), val));
+                }
+                fields(image, heap, &obj, *c)?[*i] = val;
+            }
+            Op::Newarr(t) => {
+                let len = match pop(stack, base)? {
+                    Atom::VInt(len) if len < 0 => return Err(Fault::NegativeLength(len)),
+                    Atom::VInt(len) => len as usize,
+                    v => return Err(Fault::TypeMismatch("newarr", v, Atom::VInt(0))),
+                };
+                if gc.due(1 + len) {
+                    // the length stays for the instruction to run again
+                    stack.push(Atom::VInt(len as i32));
+                    *pc -= 1;
+                    return Ok(Flow::Collect(1 + len));
+                }
+                let mut items = Vec::new();
+                if items.try_reserve_exact(len).is_err() {
+                    return Err(Fault::AllocationFailed(1 + len));
+                }
+                items.resize(len, Atom::zero(t));
+                let arr = Atom::Array(Box::new(Array {elem: t.clone(), items}));
+                stack.push(Atom::Ref(Memory::alloc(heap, free, gc, arr)));
+            }
+            Op::Aload => {
+                let i = pop(stack, base)?;
+                let arr = array(heap, &pop(stack, base)?)?;
+                let i = index(arr, i)?;
+                match &arr.items[i] {
+                    Atom::Null => return Err(Fault::UnsetElement(i)),
+                    val => stack.push(val.clone()),
+                }
+            }
+            Op::Astore => {
+                let val = pop(stack, base)?;
+                let i = pop(stack, base)?;
+                let arr = pop(stack, base)?;
+                let found = array(heap, &arr)?;
+                let i = index(found, i)?;
+                let fits = match val {
+                    // what a reference refers to is elsewhere on the heap
+                    Atom::Ref(_) => {
+                        let elem = found.elem.clone();
+                        image.conforms(heap, &val, &elem)
+                    }
+                    _ => val.has_type(&found.elem),
+                };
+                let found = array(heap, &arr)?;
+                if ! fits {
+                    return Err(Fault::ElementType(found.elem.clone(), val));
+                }
+                found.items[i] = val;
+            }
+            Op::Alen => {
+                let arr = array(heap, &pop(stack, base)?)?;
+                stack.push(Atom::VInt(arr.items.len() as i32));
+            }
+            Op::Callm(m, nargs) => {
+                if stack.len() - base < *nargs || *nargs == 0 {
+                    return Err(Fault::ArgumentCount(*nargs, stack.len() - base));
+                }
+                let c = class_of(image, heap, &stack[stack.len() - nargs])?;
+                match image.classes[c].vtable[*m] {
+                    Some(func) => return Ok(Flow::Call(func)),
+                    None => {
+                        let class = image.classes[c].name.clone();
+                        return Err(Fault::NoMethod(class, image.methods[*m].clone()));
+                    }
+                }
+            }
+            Op::Call(func) => return Ok(Flow::Call(*func)),
+            Op::TailCall(func) => return Ok(Flow::TailCall(*func)),
+            Op::Ret => return Ok(Flow::Return),
+            Op::Nop => (),
+        }
+        if STEP {
+            return Ok(Flow::Step);
+        }
+    }
+    return Ok(Flow::Return);
+}
+
+/**
+ * Runs the functions of an `Image` on a `Memory`.
+ */
+pub struct Machine<'p> {
+    image      : &'p Image,
+    host       : &'p Host,
+    pub mem    : Memory,
+    /// where the builtins read and write, the standard streams by default
+    pub io     : Console,
+    jit        : Option<Jit>,
+    /// how often each call site ran, by function and instruction index
+    calls      : Option<HashMap<(usize, usize), u64>>,
+    /// active calls beyond which a call faults
+    max_frames : usize
+}
+
+impl<'p> Machine<'p> {
+    /**
+     * A machine running `image` on `mem`, with the `host` it is linked with.
+     */
+    pub fn new(image : &'p Image, host : &'p Host, mem : Memory) -> Machine<'p> {
+        return Machine {
+            image, host, mem,
+            io: Console::stdio(),
+            jit: None,
+            calls: None,
+            max_frames: usize::MAX
+        };
+    }
+
+    /**
+     * Faults calls that would make more than `frames` calls active.
+     */
+    pub fn limit_frames(&mut self, frames : usize) {
+        self.max_frames = frames;
+    }
+
+    /**
+     * Counts the calls made from each call site, see `profile`.
+     */
+    pub fn enable_profile(&mut self) {
+        self.calls = Some(HashMap::new());
+    }
+
+    /**
+     * The calls counted since `enable_profile`.
+     */
+    pub fn profile(&self) -> Profile {
+        let mut profile = Profile::default();
+        for ((func, pc), count) in self.calls.iter().flatten() {
+            let caller = &self.image.funcs[*func];
+            let callee = match caller.code[*pc] {
+                Op::Call(g) | Op::TailCall(g) => &self.image.funcs[g],
+                _ => continue,
+            };
+            let key = (caller.name.clone(), caller.rows[*pc], callee.name.clone());
+            *profile.calls.entry(key).or_insert(0) += count;
+        }
+        return profile;
+    }
+
+    /**
+     * Runs int-only functions as native code once they have been called
+     * `threshold` times.
+     */
+    pub fn enable_jit(&mut self, threshold : u32) {
+        self.jit = Some(Jit::new(self.image, threshold));
+    }
+
+    /**
+     * The names of the functions the JIT has compiled, if enabled.
+     */
+    pub fn compiled(&self) -> Vec<String> {
+        return match &self.jit {
+            Some(jit) => jit.compiled().into_iter().map(|f| self.image.funcs[f].name.clone()).collect(),
+            None => Vec::new(),
+        };
+    }
+
+    /**
+     * Calls function `func` of the image with the arguments on top of the
+     * stack, and runs it until it returns.
+     */
+    pub fn call(&mut self, func : usize) -> Result<(), VmError> {
+        let depth = self.mem.frames.len();
+        self.start(func)?;
+        return self.run(depth);
+    }
+
+    /**
+     * Calls function `func` like `call`, but stops before its first
+     * instruction, for `step` to run it.
+     */
+    pub fn start(&mut self, func : usize) -> Result<(), VmError> {
+        if let Err(fault) = self.enter(func) {
+            return Err(self.trace(fault));
+        }
+        return Ok(());
+    }
+
+    /**
+     * Runs the next instruction of the innermost call, if there is one,
+     * entering or leaving calls as it does.
+     */
+    pub fn step(&mut self) -> Result<(), VmError> {
+        let image = self.image;
+        loop {
+            let (func, mut pc, base) = match self.mem.frames.last() {
+                Some(frame) => (frame.func, frame.pc, frame.base),
+                None => return Ok(()),
+            };
+            let code = &image.funcs[func].code;
+            let flow = dispatch::<true>(image, self.host, &mut self.io, code, &mut pc, base, &mut self.mem);
+            self.mem.frames.last_mut().unwrap().pc = pc;
+            let collect = matches!(flow, Ok(Flow::Collect(_)));
+            if let Err(fault) = self.follow(flow) {
+                return Err(self.trace(fault));
+            }
+            // the instruction that was due for a collection is still to run
+            if ! collect {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn image(&self) -> &'p Image {
+        return self.image;
+    }
+
+    /**
+     * Runs until only `depth` frames are left.
+     */
+    fn run(&mut self, depth : usize) -> Result<(), VmError> {
+        let image = self.image;
+        while self.mem.frames.len() > depth {
+            let (func, mut pc, base) = {
+                let frame = self.mem.frames.last().unwrap();
+                (frame.func, frame.pc, frame.base)
+            };
+            let code = &image.funcs[func].code;
+            let flow = dispatch::<false>(image, self.host, &mut self.io, code, &mut pc, base, &mut self.mem);
+            self.mem.frames.last_mut().unwrap().pc = pc;
+            if let (Some(calls), Ok(Flow::Call(_) | Flow::TailCall(_))) = (&mut self.calls, &flow) {
+                *calls.entry((func, pc - 1)).or_insert(0) += 1;
+            }
+            if let Err(fault) = self.follow(flow) {
+                return Err(self.trace(fault));
+            }
+        }
+        return Ok(());
+    }
+
+    /**
+     * Does what the dispatch loop handed control back for.
+     */
+    fn follow(&mut self, flow : Result<Flow, Fault>) -> Result<(), Fault> {
+        return match flow {
+            Ok(Flow::Call(callee)) => self.enter(callee),
+            Ok(Flow::TailCall(callee)) => self.tail_call(callee),
+            Ok(Flow::Return) => self.leave(),
+            Ok(Flow::Collect(size)) => {
+                gc::collect(&mut self.mem);
+                let room = self.mem.gc.make_room(size);
+                if room.is_err() {
+                    // the fault is that of the allocation, which is yet to run
+                    self.mem.frames.last_mut().unwrap().pc += 1;
+                }
+                room
+            }
+            Ok(Flow::Step) => Ok(()),
+            Err(fault) => Err(fault),
+        };
+    }
+
+    /**
+     * Pushes a frame for `func`, whose operand stack starts with the
+     * arguments popped from the stack of its caller.
+     */
+    fn enter(&mut self, func : usize) -> Result<(), Fault> {
+        let callee = &self.image.funcs[func];
+        let stack = &self.mem.stack;
+        let floor = self.mem.frames.last().map_or(0, |f| f.base);
+        let nargs = callee.par_ts.len();
+        if stack.len() - floor < nargs {
+            return Err(Fault::ArgumentCount(nargs, stack.len() - floor));
+        }
+        let base = stack.len() - nargs;
+        for (i, (arg, t)) in stack[base..].iter().zip(&callee.par_ts).enumerate() {
+            if ! self.image.conforms(&self.mem.heap, arg, t) {
+                return Err(Fault::ArgumentType(i, t.clone(), arg.clone()));
+            }
+        }
+        if self.mem.frames.len() >= self.max_frames {
+            return Err(Fault::StackOverflow(self.max_frames));
+        }
+        if let Some(jit) = &mut self.jit {
+            let args : Vec<i32> = stack[base..].iter().map(|arg| match arg {
+                Atom::VInt(i) => *i,
+                _ => 0,
+            }).collect();
+            // native calls count against the limit like interpreted ones
+            let frames = self.max_frames - self.mem.frames.len();
+            if let Some(val) = jit.call(self.image, func, &args, frames) {
+                self.mem.stack.truncate(base);
+                if callee.ret_t.arity() == 1 {
+                    self.mem.stack.push(Atom::VInt(val));
+                }
+                return Ok(());
+            }
+        }
+        self.mem.frames.push(Frame {
+            func, pc: 0, base,
+            locals: vec![Atom::Null; callee.nlocals]
+        });
+        return Ok(());
+    }
+
+    /**
+     * Replaces the innermost frame by one for `func`, if the arguments
+     * are all that is left on its stack. The callee then returns straight
+     * to the caller of the replaced frame, whose result it shares.
+     */
+    fn tail_call(&mut self, func : usize) -> Result<(), Fault> {
+        let frame = self.mem.frames.last().unwrap();
+        let nargs = self.image.funcs[func].par_ts.len();
+        if self.mem.stack.len() - frame.base != nargs {
+            return self.enter(func);
+        }
+        self.mem.frames.pop();
+        return self.enter(func);
+    }
+
+    /**
+     * Pops the innermost frame, checking that it leaves exactly the
+     * values its `ret_t` declares. They stay on the stack for the caller.
+     */
+    fn leave(&mut self) -> Result<(), Fault> {
+        let frame = self.mem.frames.last().unwrap();
+        let callee = &self.image.funcs[frame.func];
+        let found = self.mem.stack.len() - frame.base;
+        if found != callee.ret_t.arity() {
+            return Err(Fault::ReturnCount(callee.ret_t.arity(), found));
+        }
+        if let Some(val) = self.mem.stack.last() {
+            if found == 1 && ! self.image.conforms(&self.mem.heap, val, &callee.ret_t) {
+                return Err(Fault::ReturnType(callee.ret_t.clone(), val.clone()));
+            }
+        }
+        self.mem.frames.pop();
+        return Ok(());
+    }
+
+    /**
+     * Attaches the backtrace of the active calls to a fault.
+     */
+    fn trace(&self, fault : Fault) -> VmError {
+        let mut err = VmError::from(fault);
+        for frame in self.mem.frames.iter().rev() {
+            let func = &self.image.funcs[frame.func];
+            let pc = frame.pc.saturating_sub(1);
+            err.trace.push(TraceFrame {
+                function: func.name.clone(),
+                pc,
+                row: func.rows.get(pc).cloned().unwrap_or(0)
+            });
+        }
+        return err;
+    }
+}
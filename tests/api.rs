@@ -0,0 +1,359 @@
+/*!
+ * Embeds the VM through the `rust_vm` library: loads programs, registers
+ * host functions and calls functions of the program by name.
+ */
+
+#![allow(clippy::needless_return)]
+
+use rust_vm::{Atom, Buffer, Fault, LoadError, Signature, Type, Vm};
+use rust_vm::mem_alloc::Object;
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+const PROGRAM : &str = "
+.raw
+.class
+defcl Node
+field next Node
+endcl
+.function
+defun main 0 NULL
+endef
+defun init 0 NULL
+pushi 0
+gstore 0
+endef
+defun count 0 int       ; increments global 0 and returns it
+pushi 1
+gload 0
+add
+dup
+gstore 0
+endef
+defun scale 2 int int int
+store 1
+store 0
+load 1
+load 0
+call mul
+endef
+defun smuggle 0 NULL    ; passes a string where the host takes an int
+stores 0 1 \"x\"
+load 0
+gstore 1
+gload 1
+pushi 1
+call mul
+pop
+endef
+defun deep 0 NULL
+call deep
+pushi 0
+pop
+endef
+defun hoard 0 NULL      ; allocates a list it keeps, forever
+new Node
+store 0
+label 1
+new Node
+dup
+load 0
+setfield Node next
+store 0
+goto 1
+endef
+defun node 0 Node
+new Node
+endef
+defun link 1 Node Node  ; a new node before the one given
+store 0
+new Node
+dup
+load 0
+setfield Node next
+endef
+defun down 1 int int    ; recurses n deep and returns 0
+store 0
+load 0
+branch 0
+pushi 0
+ret
+label 0
+pushi 1
+load 0
+sub
+call down
+pushi 0
+add
+endef
+";
+
+fn int(val : &Atom) -> i32 {
+    return match val {
+        Atom::VInt(i) => *i,
+        v => panic!("{:?} is not an int", v),
+    };
+}
+
+/**
+ * A VM with `PROGRAM` loaded and a host function `mul` that records the
+ * products it computes in `log`.
+ */
+fn load(log : &Rc<RefCell<Vec<i32>>>) -> Vm {
+    let mut vm = Vm::new();
+    let log = Rc::clone(log);
+    vm.register("mul", Signature::new(vec![Type::TInt, Type::TInt], Type::TInt), move |args, _| {
+        let product = int(&args[0]) * int(&args[1]);
+        log.borrow_mut().push(product);
+        return Ok(Atom::VInt(product));
+    });
+    if let Err(e) = vm.load_text(PROGRAM) {
+        panic!("{}", e);
+    }
+    return vm;
+}
+
+#[test]
+fn calls_functions_by_name() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = load(&log);
+    assert!(matches!(vm.call("init", &[]), Ok(Atom::Null)));
+    // globals stay from one call to the next
+    for n in 1..4 {
+        assert_eq!(int(&vm.call("count", &[]).unwrap()), n);
+    }
+    let found = vm.call("scale", &[Atom::VInt(6), Atom::VInt(7)]).unwrap();
+    assert_eq!(int(&found), 42);
+    assert_eq!(*log.borrow(), vec![42]);
+
+    let node = vm.call("node", &[]).unwrap();
+    match node {
+        Atom::Ref(r) => assert!(matches!(&vm.memory().heap[r], Atom::Object(obj) if obj.class == "Node")),
+        v => panic!("{:?} is not a reference", v),
+    }
+}
+
+#[test]
+fn checks_calls() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = load(&log);
+    let fault = |result : Result<Atom, rust_vm::VmError>| result.unwrap_err().fault;
+    assert!(matches!(fault(vm.call("missing", &[])), Fault::UnknownFunction(_)));
+    assert!(matches!(fault(vm.call("scale", &[Atom::VInt(1)])), Fault::ArgumentCount(2, 1)));
+    let three = [Atom::VInt(1), Atom::VInt(2), Atom::VInt(3)];
+    assert!(matches!(fault(vm.call("scale", &three)), Fault::ArgumentCount(2, 3)));
+    let sig = Signature::new(vec![Type::TInt, Type::TInt], Type::Void);
+    assert!(matches!(sig.check_args(&three), Err(Fault::ArgumentCount(2, 3))));
+    assert!(matches!(sig.check_args(&three[..1]), Err(Fault::ArgumentCount(2, 1))));
+    assert!(matches!(fault(vm.call("scale", &[Atom::VInt(1), Atom::Null])), Fault::ArgumentType(1, _, _)));
+    // the type checker cannot see through globals, the host call can
+    assert!(matches!(fault(vm.call("smuggle", &[])), Fault::ArgumentType(0, Type::TInt, _)));
+    assert!(log.borrow().is_empty());
+    // a fault leaves the VM ready for the next call
+    assert_eq!(int(&vm.call("scale", &[Atom::VInt(2), Atom::VInt(3)]).unwrap()), 6);
+}
+
+#[test]
+fn checks_host_returns() {
+    let mut vm = Vm::new();
+    vm.register("mul", Signature::new(vec![Type::TInt, Type::TInt], Type::TInt), |_, _| {
+        return Ok(Atom::VString("six".to_string()));
+    });
+    vm.load_text(PROGRAM).unwrap();
+    let found = vm.call("scale", &[Atom::VInt(2), Atom::VInt(3)]).unwrap_err();
+    assert!(matches!(found.fault, Fault::ReturnType(Type::TInt, _)));
+    assert_eq!(found.trace[0].function, "scale");
+}
+
+#[test]
+fn checks_what_the_host_allocates() {
+    let mut vm = Vm::new();
+    let sig = Signature::new(vec![Type::TInt], Type::TClass("Node".to_string()));
+    vm.register("make", sig, |args, ctx| {
+        let object = |class : &str, fields : Vec<Atom>| {
+            return Atom::Object(Box::new(Object {class: class.to_string(), fields}));
+        };
+        return ctx.alloc(match int(&args[0]) {
+            0 => object("Node", vec![Atom::Null]),
+            1 => object("Leaf", vec![]),
+            2 => object("Node", vec![]),
+            3 => object("Node", vec![Atom::VInt(1)]),
+            _ => Atom::VInt(1),
+        });
+    });
+    vm.load_text(".raw .class defcl Node field next Node endcl .function \
+        defun main 0 NULL endef defun node 1 int Node store 0 load 0 call make endef").unwrap();
+    assert!(matches!(vm.call("node", &[Atom::VInt(0)]), Ok(Atom::Ref(_))));
+    let fault = |vm : &mut Vm, i| vm.call("node", &[Atom::VInt(i)]).unwrap_err().fault;
+    assert!(matches!(fault(&mut vm, 1), Fault::UnknownClass(c) if c == "Leaf"));
+    assert!(matches!(fault(&mut vm, 2), Fault::FieldCount(c, 1, 0) if c == "Node"));
+    assert!(matches!(fault(&mut vm, 3), Fault::FieldType(f, Type::TClass(_), Atom::VInt(1)) if f == "next"));
+    assert!(matches!(fault(&mut vm, 4), Fault::NotAnObject(Atom::VInt(1))));
+}
+
+#[test]
+fn rejects_unknown_host_functions() {
+    let mut vm = Vm::new();
+    match vm.load_text(PROGRAM) {
+        Err(LoadError::Verify(errors)) => {
+            assert!(errors.iter().all(|e| e.message == "mul() not found"), "{:?}", errors);
+        }
+        _ => panic!("loaded a program calling an unregistered function"),
+    }
+}
+
+#[test]
+fn checks_references_against_the_heap() {
+    // the type checker cannot see through globals, the VM can
+    let mut vm = Vm::new();
+    vm.load_text("
+        .raw
+        .class
+        defcl A
+        endcl
+        defcl C
+        extends A
+        endcl
+        defcl B
+        field a A
+        endcl
+        .function
+        defun main 0 NULL
+        endef
+        defun take 1 A int
+        store 0
+        pushi 1
+        endef
+        defun ints 0 NULL       ; global 0 holds an int[], global 1 a C
+        pushi 1
+        newarr int
+        gstore 0
+        new C
+        gstore 1
+        endef
+        defun field 0 NULL
+        call ints
+        new B
+        gload 0
+        setfield B a
+        endef
+        defun argument 0 int
+        call ints
+        gload 0
+        call take
+        endef
+        defun element 0 NULL
+        call ints
+        pushi 1
+        newarr A
+        pushi 0
+        gload 0
+        astore
+        endef
+        defun array 0 A[]
+        call ints
+        gload 0
+        endef
+        defun subclass 0 int
+        call ints
+        pushi 1
+        newarr A
+        pushi 0
+        gload 1
+        astore
+        new B
+        gload 1
+        setfield B a
+        gload 1
+        call take
+        endef
+    ").unwrap();
+    let fault = |vm : &mut Vm, name| vm.call(name, &[]).unwrap_err().fault;
+    assert!(matches!(fault(&mut vm, "field"), Fault::FieldType(_, Type::TClass(_), _)));
+    assert!(matches!(fault(&mut vm, "argument"), Fault::ArgumentType(0, Type::TClass(_), _)));
+    assert!(matches!(fault(&mut vm, "element"), Fault::ElementType(Type::TClass(_), _)));
+    assert!(matches!(fault(&mut vm, "array"), Fault::ReturnType(Type::TArray(_), _)));
+    assert_eq!(int(&vm.call("subclass", &[]).unwrap()), 1);
+}
+
+#[test]
+fn keeps_only_the_references_passed_back() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = load(&log);
+    // collect before every allocation but the first
+    vm.set_gc(1, 1.0);
+    let dropped = vm.call("node", &[]).unwrap();
+    let node = vm.call("node", &[]).unwrap();
+    assert_eq!(vm.memory().gc.stats.freed, 1);
+    assert!(matches!((&dropped, &node), (Atom::Ref(a), Atom::Ref(b)) if a == b));
+
+    let collections = vm.memory().gc.stats.collections;
+    let head = vm.call("link", std::slice::from_ref(&node)).unwrap();
+    assert!(vm.memory().gc.stats.collections > collections);
+    assert_eq!(vm.memory().gc.stats.freed, 1);
+    let (node, head) = match (node, head) {
+        (Atom::Ref(node), Atom::Ref(head)) => (node, head),
+        v => panic!("{:?} are not references", v),
+    };
+    assert_ne!(node, head);
+    match &vm.memory().heap[head] {
+        Atom::Object(obj) => assert!(matches!(obj.fields[0], Atom::Ref(r) if r == node)),
+        v => panic!("{:?} is not an object", v),
+    }
+}
+
+#[test]
+fn enforces_limits() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = load(&log);
+    vm.limit_frames(100);
+    vm.limit_heap(50);
+    assert!(matches!(vm.call("deep", &[]).unwrap_err().fault, Fault::StackOverflow(100)));
+    assert!(matches!(vm.call("hoard", &[]).unwrap_err().fault, Fault::OutOfMemory(50)));
+    assert!(vm.memory().gc.stats.collections > 0);
+
+    // the elements of an array count, however many are asked for
+    vm.load_text(".raw .class .function defun main 0 NULL endef \
+        defun array 1 int int[] store 0 load 0 newarr int endef").unwrap();
+    assert!(vm.call("array", &[Atom::VInt(49)]).is_ok());
+    for len in [50, 2000000000] {
+        let found = vm.call("array", &[Atom::VInt(len)]).unwrap_err();
+        assert!(matches!(found.fault, Fault::OutOfMemory(50)));
+        assert_eq!(found.trace[0].pc, 2);
+    }
+}
+
+#[test]
+fn limits_native_calls() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = load(&log);
+    vm.enable_jit(1);
+    vm.limit_frames(100);
+    assert_eq!(int(&vm.call("down", &[Atom::VInt(99)]).unwrap()), 0);
+    assert!(matches!(vm.call("down", &[Atom::VInt(500)]).unwrap_err().fault, Fault::StackOverflow(100)));
+}
+
+#[test]
+fn reads_and_writes_the_console_given() {
+    let mut vm = Vm::new();
+    let out = Buffer::new();
+    vm.set_io(Box::new(&b" 3\n\n4 5"[..]), Box::new(out.clone()));
+    vm.register("shout", Signature::any(1, Type::Void), |args, ctx| {
+        let out = ctx.io.output();
+        writeln!(out, "{:?}!", args[0]).map_err(|e| Fault::Io(e.to_string()))?;
+        return Ok(Atom::Null);
+    });
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("apb.ri");
+    vm.load_bytes(&std::fs::read(path).unwrap()).unwrap();
+    vm.call("main", &[]).unwrap();
+    assert_eq!(out.contents(), "a = b = 7\n");
+    // the input left over is read by the next call
+    vm.call("main", &[]).unwrap_err();
+    assert_eq!(out.contents(), "a = b = 7\na = b = ");
+
+    vm.load_text(".raw .class .function defun main 0 NULL pushi 7 call shout endef").unwrap();
+    vm.call("main", &[]).unwrap();
+    assert!(out.contents().ends_with("VInt(7)!\n"));
+}
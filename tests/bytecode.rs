@@ -0,0 +1,238 @@
+/*!
+ * Reads and writes programs in both encodings, including malformed ones,
+ * which must be reported rather than abort.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+use rust_vm::{bytecode, parse_bytes, parse_text, LoadError};
+
+/// the format version the encodings below are written in
+const VERSION : u16 = 2;
+
+fn string(data : &mut Vec<u8>, text : &str) {
+    data.extend((text.len() as u16).to_le_bytes());
+    data.extend(text.as_bytes());
+}
+
+/**
+ * `tests/helloworld.ri` in the byte encoding, as spec.md describes it.
+ */
+fn hello() -> Vec<u8> {
+    let mut data = b"RVMB".to_vec();
+    data.extend(VERSION.to_le_bytes());
+    data.extend([0x00, 0x01, 0x02]);
+    data.push(0x10);
+    string(&mut data, "main");
+    data.extend([0, 3]);
+    data.push(0x26);
+    data.extend(0u16.to_le_bytes());
+    string(&mut data, "hello, world");
+    data.push(0x24);
+    data.extend(0u16.to_le_bytes());
+    data.push(0x40);
+    string(&mut data, "println");
+    data.push(0x11);
+    return data;
+}
+
+/**
+ * Checks that `found`, a run of an encoding of `program`, behaves like a
+ * run of `program` itself. Error messages name other lines once the
+ * program is re-encoded, so only their presence is compared.
+ */
+fn same_run(program : &Path, found : &Output) {
+    let expected = common::run(program, &[]);
+    let name = program.display();
+    assert_eq!(found.status.code(), expected.status.code(), "{}", name);
+    assert_eq!(String::from_utf8_lossy(&found.stdout), String::from_utf8_lossy(&expected.stdout), "{}", name);
+    assert_eq!(found.stderr.is_empty(), expected.stderr.is_empty(), "{}", name);
+}
+
+fn input(program : &Path) -> Vec<u8> {
+    return fs::read(program.with_extension("in")).unwrap_or_default();
+}
+
+/**
+ * Assembles `text` and replaces the first string of the encoding that
+ * reads `from` by `to`.
+ */
+fn patched(dir : &Path, text : &str, from : &str, to : &str) -> Vec<u8> {
+    let path = dir.join("patched.ri");
+    fs::write(&path, text).unwrap();
+    let encoded = path.with_extension("rbc");
+    assert!(common::rvmi(&[Path::new("asm"), &path, Path::new("-o"), &encoded], b"").status.success());
+    let data = fs::read(&encoded).unwrap();
+    let (mut old, mut new) = (Vec::new(), Vec::new());
+    string(&mut old, from);
+    string(&mut new, to);
+    let at = data.windows(old.len()).position(|w| w == old).unwrap();
+    return [&data[..at], &new, &data[at + old.len()..]].concat();
+}
+
+#[test]
+fn reads_the_encoding_of_the_spec() {
+    let path = common::scratch("reads_the_encoding").join("hello.rbc");
+    fs::write(&path, hello()).unwrap();
+    let found = common::rvmi(&[&path], b"");
+    assert!(found.status.success(), "{}", String::from_utf8_lossy(&found.stderr));
+    assert_eq!(String::from_utf8_lossy(&found.stdout), "hello, world\n");
+}
+
+#[test]
+fn reads_only_versions_with_the_same_meaning() {
+    let dir = common::scratch("reads_only_versions");
+    let program = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("helloworld.ri");
+    let written = dir.join("hello.rbc");
+    assert!(common::rvmi(&[Path::new("asm"), &program, Path::new("-o"), &written], b"").status.success());
+    let header = fs::read(&written).unwrap();
+    let current = u16::from_le_bytes([header[4], header[5]]);
+    // version 1 had global locals, and a later version is unknown
+    for (version, ok) in [(1, false), (VERSION, true), (current, true), (current + 1, false)] {
+        let path = dir.join(format!("{}.rbc", version));
+        fs::write(&path, [&hello()[..4], &version.to_le_bytes(), &hello()[6..]].concat()).unwrap();
+        let found = common::rvmi(&[&path], b"");
+        let stderr = String::from_utf8_lossy(&found.stderr);
+        if ok {
+            assert!(found.status.success(), "{}: {}", version, stderr);
+        } else {
+            assert_eq!(found.status.code(), Some(1), "{}", version);
+            assert!(stderr.contains(&format!("unsupported byte encoding version {}", version)), "{}", stderr);
+        }
+    }
+}
+
+#[test]
+fn rejects_what_is_newer_than_the_version() {
+    // the last byte equal to the code is the opcode or type tag that came
+    // with version `since`
+    let cases : [(&str, u8, u16); 3] = [
+        (".raw .class .function defun main 0 NULL ret endef", 0x44, 3),
+        (NEWARR, 0x54, 6),
+        (".raw .class .function defun main 0 NULL endef defun f 1 int[] NULL pop endef", 5, 6),
+    ];
+    for (text, code, since) in cases {
+        let data = bytecode::write(&parse_text(text).unwrap()).unwrap();
+        let at = data.iter().rposition(|b| *b == code).unwrap();
+        for version in [since - 1, since] {
+            let old = [&data[..4], &version.to_le_bytes(), &data[6..]].concat();
+            match (parse_bytes(&old), version < since) {
+                (Ok(_), false) => (),
+                (Err(LoadError::Bytecode(bytecode::Error::NewerOpcode(found, op, v))), true) |
+                (Err(LoadError::Bytecode(bytecode::Error::NewerType(found, op, v))), true) => {
+                    assert_eq!((found, op, v), (at, code, version), "{}", text);
+                }
+                (found, _) => panic!("{} in version {}: {:?}", text, version, found.err()),
+            }
+        }
+    }
+}
+
+#[test]
+fn rejects_malformed_bytes() {
+    let dir = common::scratch("rejects_malformed_bytes");
+    let data = hello();
+    let string_at = data.windows(5).position(|w| w == b"hello").unwrap();
+    let mut cases = vec![
+        ("version", [&data[..4], &99u16.to_le_bytes(), &data[6..]].concat(), "unsupported byte encoding version 99"),
+        ("opcode", [&data[..6], &[0xff]].concat(), "offset 6: unknown opcode 0xff"),
+        ("type", [&data[..17], &[9], &data[18..]].concat(), "offset 17: unknown type tag 9"),
+        ("utf8", [&data[..string_at], &[0xff], &data[string_at + 1..]].concat(), "string is not valid UTF-8"),
+    ];
+    // cut within every operand of the first instructions
+    for at in [11, 14, 17, 20, string_at + 3] {
+        cases.push(("eof", data[..at].to_vec(), "unexpected end of file"));
+    }
+    for (what, bytes, expected) in cases {
+        let path = dir.join(what).with_extension("rbc");
+        fs::write(&path, &bytes).unwrap();
+        let found = common::rvmi(&[&path], b"");
+        let stderr = String::from_utf8_lossy(&found.stderr);
+        assert_eq!(found.status.code(), Some(1), "{}: {}", what, stderr);
+        assert!(stderr.contains(expected), "{}: {}", what, stderr);
+    }
+}
+
+#[test]
+fn asm_and_disasm_round_trip() {
+    let dir = common::scratch("asm_and_disasm");
+    for program in common::programs() {
+        let stem = dir.join(program.file_stem().unwrap());
+        let (first, text, second) = (stem.with_extension("rbc"), stem.with_extension("ri"), stem.with_extension("2.rbc"));
+        let steps : [&[&Path]; 3] = [
+            &[Path::new("asm"), &program, Path::new("-o"), &first],
+            &[Path::new("disasm"), &first, Path::new("-o"), &text],
+            &[Path::new("asm"), &text, Path::new("-o"), &second],
+        ];
+        for args in steps {
+            let found = common::rvmi(args, b"");
+            assert!(found.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&found.stderr));
+        }
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap(), "{}", program.display());
+        same_run(&program, &common::run_with(&first, &[], &input(&program)));
+        same_run(&program, &common::run_with(&text, &[], &input(&program)));
+    }
+}
+
+#[test]
+fn disassembles_only_what_reads_back() {
+    let dir = common::scratch("disassembles_only_what_reads_back");
+    let path = dir.join("bad.rbc");
+    let call = ".raw\n.class\n.function\ndefun main 0 NULL\ncall fff\nendef\n";
+    for name in ["a b", "a\tb", "a\nb", "a\rb", "", ";ab", "\"ab"] {
+        fs::write(&path, patched(&dir, call, "fff", name)).unwrap();
+        let found = common::rvmi(&[Path::new("disasm"), &path], b"");
+        let stderr = String::from_utf8_lossy(&found.stderr);
+        assert_eq!(found.status.code(), Some(1), "{:?}", name);
+        assert!(stderr.contains("cannot be written in the raw encoding"), "{:?}: {}", name, stderr);
+    }
+    // a class named like a builtin type would read back as that type
+    let param = ".raw\n.class\n.function\ndefun main 0 NULL\nendef\ndefun f 1 Foo NULL\npop\nendef\n";
+    fs::write(&path, patched(&dir, param, "Foo", "int")).unwrap();
+    assert_eq!(common::rvmi(&[Path::new("disasm"), &path], b"").status.code(), Some(1));
+}
+
+const NEWARR : &str = ".raw .class .function defun main 0 NULL pushi 1 newarr int pop endef";
+
+/**
+ * `NEWARR` with the element type nested `dims` arrays deep.
+ */
+fn nested(dims : usize) -> String {
+    return NEWARR.replace("newarr int", &format!("newarr int{}", "[]".repeat(dims)));
+}
+
+#[test]
+fn rejects_deeply_nested_types() {
+    assert!(parse_text(&nested(32)).is_ok());
+    match parse_text(&nested(200000)) {
+        Err(LoadError::Parse(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!((errors[0].line, errors[0].column), (1, 56));
+            assert_eq!(errors[0].expected, "an element type after `newarr`");
+        }
+        _ => panic!("parsed a type nested 200000 deep"),
+    }
+
+    // the type tag of the element type follows the opcode of `newarr`
+    let data = bytecode::write(&parse_text(NEWARR).unwrap()).unwrap();
+    let at = data.windows(2).position(|w| w == [0x54, 0]).unwrap() + 1;
+    for (dims, ok) in [(32, true), (33, false), (2000000, false)] {
+        let mut nested = data[..at].to_vec();
+        nested.extend(std::iter::repeat_n(5, dims));
+        nested.extend(&data[at..]);
+        match parse_bytes(&nested) {
+            Ok(_) => assert!(ok, "read a type nested {} deep", dims),
+            Err(LoadError::Bytecode(bytecode::Error::TooDeep(offset))) => {
+                assert!(! ok);
+                assert_eq!(offset, at);
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
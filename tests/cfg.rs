@@ -0,0 +1,34 @@
+/*!
+ * Draws function `f` of every program in `tests/cfg/` with `rvmi cfg`
+ * and compares the graph with the `.dot` file of the same name.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("cfg");
+    let mut found : Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ri"))
+        .collect();
+    found.sort();
+    return found;
+}
+
+#[test]
+fn draws_the_expected_graph() {
+    let programs = programs();
+    assert!(! programs.is_empty());
+    for program in &programs {
+        let args = [Path::new("cfg"), program, Path::new("--function"), Path::new("f")];
+        let output = common::rvmi(&args, &[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let expected = fs::read_to_string(program.with_extension("dot")).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected, "{}", program.display());
+    }
+}
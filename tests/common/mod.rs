@@ -0,0 +1,103 @@
+/*!
+ * Helpers shared by the tests that run `rvmi`.
+ */
+
+#![allow(clippy::needless_return)]
+#![allow(dead_code)]
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/**
+ * Every `.ri` program in `tests/`, sorted by name.
+ */
+pub fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut found : Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ri"))
+        .collect();
+    found.sort();
+    return found;
+}
+
+/**
+ * Runs `program` with `flags`, feeding it the `.in` file of the same name
+ * as standard input, if there is one.
+ */
+pub fn run(program : &Path, flags : &[&str]) -> Output {
+    let input = fs::read(program.with_extension("in")).unwrap_or_default();
+    return run_with(program, flags, &input);
+}
+
+/**
+ * Runs `program` with `flags`, feeding it `input` as standard input.
+ */
+pub fn run_with(program : &Path, flags : &[&str], input : &[u8]) -> Output {
+    let mut args : Vec<&OsStr> = flags.iter().map(OsStr::new).collect();
+    args.push(program.as_os_str());
+    return rvmi(&args, input);
+}
+
+/**
+ * A directory of its own for the files the test `name` writes.
+ */
+pub fn scratch(name : &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::create_dir_all(&dir).unwrap();
+    return dir;
+}
+
+/**
+ * Runs `rvmi` with `args`, feeding it `input` as standard input.
+ */
+pub fn rvmi<S : AsRef<OsStr>>(args : &[S], input : &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rvmi"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    return child.wait_with_output().unwrap();
+}
+
+/**
+ * How much of the error output `compare_runs` compares: error messages
+ * may name other instruction indices once a program is optimized.
+ */
+pub enum Errors {
+    Same,
+    /// only whether there is any
+    Present
+}
+
+/**
+ * Runs every program in `tests/` with `base` and then with each of
+ * `variants` as flags, and checks that the exit status, the output and
+ * the error output do not change.
+ */
+pub fn compare_runs(base : &[&str], variants : &[&[&str]], errors : Errors) {
+    let programs = programs();
+    assert!(! programs.is_empty());
+    for program in &programs {
+        let expected = run(program, base);
+        for flags in variants {
+            let found = run(program, flags);
+            let name = program.display();
+            assert_eq!(found.status.code(), expected.status.code(), "{} {:?}", name, flags);
+            assert_eq!(String::from_utf8_lossy(&found.stdout),
+                String::from_utf8_lossy(&expected.stdout), "{} {:?}", name, flags);
+            match errors {
+                Errors::Same => assert_eq!(String::from_utf8_lossy(&found.stderr),
+                    String::from_utf8_lossy(&expected.stderr), "{} {:?}", name, flags),
+                Errors::Present => assert_eq!(found.stderr.is_empty(), expected.stderr.is_empty(),
+                    "{} {:?}", name, flags),
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+/*!
+ * Drives `rvmi debug` with scripted commands, the input of the program
+ * interleaved with them, and checks where it stops and what it shows.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::path::PathBuf;
+
+fn program(name : &str) -> PathBuf {
+    return common::programs().into_iter()
+        .find(|path| path.ends_with(name))
+        .unwrap();
+}
+
+/**
+ * What the debugger prints for `commands`, one reply per line.
+ */
+fn debug(name : &str, commands : &str) -> Vec<String> {
+    let found = common::run_with(&program(name), &["debug"], commands.as_bytes());
+    assert!(found.status.success(), "{}", String::from_utf8_lossy(&found.stderr));
+    return String::from_utf8_lossy(&found.stdout)
+        .split("(rvmi) ")
+        .map(|reply| reply.to_string())
+        .collect();
+}
+
+#[test]
+fn stops_at_breakpoints_and_steps() {
+    // fib reads n between `continue` and `locals`
+    let replies = debug("fib.ri", "break fib\ncontinue\n4\nlocals\nstack\nbt\nstep\n\nnext\nq\n");
+    assert_eq!(replies[0], "main (instruction 0, line 22): stores 2 23 \"fib(n) calculator: n = \"\n");
+    assert_eq!(replies[1], "breakpoint 1 at fib\n");
+    assert_eq!(replies[2], "fib(n) calculator: n = breakpoint 1\nfib (instruction 0, line 5): store 0\n");
+    assert_eq!(replies[3], "0: unset\n1: unset\n");
+    assert_eq!(replies[4], "0: 4\n1: 1\n");
+    assert_eq!(replies[5], "#0 fib (instruction 0, line 5): store 0\n#1 main (instruction 5, line 27): call fib\n");
+    assert_eq!(replies[6], "fib (instruction 1, line 6): store 1\n");
+    // an empty line repeats `step`
+    assert_eq!(replies[7], "fib (instruction 2, line 7): load 1\n");
+    assert_eq!(replies[8], "fib (instruction 3, line 8): branch 0\n");
+    assert_eq!(replies.len(), 10);
+}
+
+#[test]
+fn runs_to_the_end() {
+    let replies = debug("fib.ri", "break 9\ninfo\ndelete 1\ninfo\ncontinue\n5\nwhere\nq\n");
+    assert_eq!(replies[2], "1: breakpoint at line 9\n");
+    assert_eq!(replies[4], "");
+    assert_eq!(replies[5], "fib(n) calculator: n = 120\nprogram exited normally\n");
+    assert_eq!(replies[6], "the program has ended: exited normally\n");
+}
+
+#[test]
+fn watches_the_heap() {
+    let replies = debug("point.ri", "watch 2\nc\nheap 2\nc\ndelete 1\nbreak length2\nc\nheap\nfinish\nheap 1000\nq\n");
+    // the heap is empty when the watchpoint is set
+    assert_eq!(replies[1], "watchpoint 1 on heap 2\n");
+    assert!(replies[2].starts_with("watchpoint 1: heap 2 changed from empty to Point {x = unset, y = unset}\n"),
+        "{}", replies[2]);
+    assert_eq!(replies[3], "2: Point {x = unset, y = unset}\n");
+    assert!(replies[4].starts_with("watchpoint 1: heap 2 changed from Point {x = unset, y = unset} to Point {x = 3, y = unset}\n"),
+        "{}", replies[4]);
+    assert_eq!(replies[8],
+        "0: Segment {from = Point@1, to = Point@2, name = \"segment\"}\n\
+         1: Point {x = 0, y = 0}\n\
+         2: Point {x = 3, y = 4}\n");
+    assert!(replies[9].contains("main (instruction"), "{}", replies[9]);
+    assert_eq!(replies[10], "bad heap address 1000\n");
+}
+
+#[test]
+fn keeps_the_calls_of_a_fault() {
+    let replies = debug("divzero.ri", "continue\nbt\nstep\nq\n");
+    assert!(replies[1].ends_with("error: division by zero\n    at quot (instruction 4, line 13)\n    at main (instruction 10, line 26)\n"),
+        "{}", replies[1]);
+    assert_eq!(replies[2], "#0 quot (instruction 4, line 13): div\n#1 main (instruction 10, line 26): call quot\n");
+    assert_eq!(replies[3], "the program has ended: division by zero\n");
+}
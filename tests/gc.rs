@@ -0,0 +1,33 @@
+/*!
+ * Runs every program in `tests/` with a collection before nearly every
+ * allocation and checks that the output does not change, and checks that
+ * the collector keeps the heap of `garbage.ri` small.
+ */
+
+mod common;
+
+use common::Errors;
+
+#[test]
+fn collector_keeps_output() {
+    common::compare_runs(&[], &[&["--gc-threshold=1"]], Errors::Same);
+}
+
+#[test]
+fn collector_frees_garbage() {
+    let program = common::programs().into_iter()
+        .find(|path| path.ends_with("garbage.ri"))
+        .unwrap();
+    let found = common::run(&program, &["--gc-threshold=64", "--gc-stats"]);
+    assert!(found.status.success());
+    assert_eq!(String::from_utf8_lossy(&found.stdout), "1010000\n20000\n");
+    let stats = String::from_utf8_lossy(&found.stderr);
+    let numbers : Vec<usize> = stats.split(' ')
+        .filter_map(|word| word.parse().ok())
+        .collect();
+    // collections, allocations, freed, cells at most in use
+    assert_eq!(numbers.len(), 4, "{}", stats);
+    assert_eq!(numbers[1], 20101, "{}", stats);
+    assert!(numbers[2] >= 19900, "{}", stats);
+    assert!(numbers[3] <= 256, "{}", stats);
+}
@@ -0,0 +1,121 @@
+/*!
+ * Runs every program in `tests/` and compares what it writes to standard
+ * output and standard error, and its exit status, with the `.out`, `.err`
+ * and `.status` files of the same name. A missing `.err` stands for no
+ * error output, and a missing `.status` for status 0.
+ *
+ *     cargo test --test golden -- --bless
+ *
+ * writes the files from the current behaviour instead, for a new program
+ * or after a change to what a program prints.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    stdout : String,
+    stderr : String,
+    status : i32
+}
+
+/**
+ * What `program` does now. Its path is shortened to the file name in
+ * error messages, so that they do not depend on the checkout.
+ */
+fn run(program : &Path) -> Outcome {
+    let output = common::run(program, &[]);
+    let name = program.file_name().unwrap().to_string_lossy();
+    let stderr = String::from_utf8_lossy(&output.stderr)
+        .replace(&program.display().to_string(), &name);
+    return Outcome {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr,
+        status: output.status.code().unwrap_or(-1)
+    };
+}
+
+/**
+ * What `program` is expected to do, `None` if it was never blessed.
+ */
+fn expected(program : &Path) -> Option<Outcome> {
+    let stdout = fs::read_to_string(program.with_extension("out")).ok()?;
+    let stderr = fs::read_to_string(program.with_extension("err")).unwrap_or_default();
+    let status = match fs::read_to_string(program.with_extension("status")) {
+        Ok(text) => text.trim().parse().expect("a .status file holds an exit status"),
+        Err(_) => 0,
+    };
+    return Some(Outcome {stdout, stderr, status});
+}
+
+fn bless(program : &Path, found : &Outcome) {
+    fs::write(program.with_extension("out"), &found.stdout).unwrap();
+    let err = program.with_extension("err");
+    let status = program.with_extension("status");
+    if found.stderr.is_empty() {
+        let _ = fs::remove_file(err);
+    } else {
+        fs::write(err, &found.stderr).unwrap();
+    }
+    if found.status == 0 {
+        let _ = fs::remove_file(status);
+    } else {
+        fs::write(status, format!("{}\n", found.status)).unwrap();
+    }
+}
+
+/**
+ * Describes how `found` differs from `expected`.
+ */
+fn differences(expected : &Outcome, found : &Outcome) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for (stream, e, f) in [("stdout", &expected.stdout, &found.stdout), ("stderr", &expected.stderr, &found.stderr)] {
+        if e != f {
+            diffs.push(format!("{} differs\n--- expected\n{}--- found\n{}---", stream, e, f));
+        }
+    }
+    if expected.status != found.status {
+        diffs.push(format!("exit status {}, expected {}", found.status, expected.status));
+    }
+    return diffs;
+}
+
+fn main() {
+    let blessing = env::args().any(|arg| arg == "--bless");
+    let programs = common::programs();
+    assert!(! programs.is_empty());
+    let mut failed = 0;
+    for program in &programs {
+        let name = program.file_name().unwrap().to_string_lossy();
+        let found = run(program);
+        if blessing {
+            bless(program, &found);
+            println!("blessed {}", name);
+            continue;
+        }
+        let diffs = match expected(program) {
+            Some(expected) => differences(&expected, &found),
+            None => vec!["no .out file, run with --bless to record one".to_string()],
+        };
+        if diffs.is_empty() {
+            println!("{} ... ok", name);
+        } else {
+            failed += 1;
+            println!("{} ... FAILED", name);
+            for diff in diffs {
+                println!("{}", diff);
+            }
+        }
+    }
+    if failed > 0 {
+        println!("{} of {} programs failed", failed, programs.len());
+        process::exit(1);
+    }
+}
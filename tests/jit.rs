@@ -0,0 +1,33 @@
+/*!
+ * Runs every program in `tests/` with and without `--jit` and checks
+ * that the outputs are identical, and checks that the functions of
+ * `arith.ri` do run as native code.
+ */
+
+mod common;
+
+use common::Errors;
+
+#[test]
+fn jit_matches_interpreter() {
+    common::compare_runs(&[], &[&["--jit"], &["--jit=1"]], Errors::Same);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn jit_compiles_int_functions() {
+    let program = common::programs().into_iter()
+        .find(|path| path.ends_with("arith.ri"))
+        .unwrap();
+    let plain = common::run(&program, &[]);
+    let found = common::run(&program, &["--jit=1", "--jit-stats"]);
+    assert!(found.status.success());
+    assert_eq!(found.stdout, plain.stdout);
+    let stats = String::from_utf8_lossy(&found.stderr);
+    let compiled : Vec<&str> = match stats.trim_end().strip_prefix("jit: compiled ") {
+        Some(names) => names.split(' ').collect(),
+        None => panic!("{}", stats),
+    };
+    // `main` prints, so the interpreter runs it
+    assert_eq!(compiled, ["between", "collatz", "gcd", "mod", "quot", "sign", "square"]);
+}
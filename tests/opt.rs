@@ -0,0 +1,58 @@
+/*!
+ * Runs every program in `tests/` at each optimization level and checks
+ * that the output does not change. Error messages may name other
+ * instruction indices, so only their presence is compared. Also checks
+ * that inlining leaves alone the calls it has no room for.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use rust_vm::opt::{self, Options};
+use rust_vm::scanner::Token;
+use rust_vm::{parse_text, Vm};
+
+use std::collections::HashMap;
+
+use common::Errors;
+
+const SQUARE : &str = ".raw .class .function defun square 1 int int store 0 load 0 load 0 mul endef";
+
+#[test]
+fn optimizer_keeps_output() {
+    common::compare_runs(&["-O0"], &[&["-O1"], &["-O2"]], Errors::Present);
+}
+
+/**
+ * Tells whether `main`, defined by `text` and changed by `patch`, still
+ * calls `square` after `-O2`.
+ */
+fn calls_square(text : &str, patch : impl Fn(&mut rust_vm::ir::Exec)) -> bool {
+    let mut program = parse_text(&format!("{} {}", SQUARE, text)).unwrap();
+    patch(&mut program.func.defs[1].exec);
+    opt::optimize(&mut program, &Options::new(2));
+    return program.func.defs[1].exec.tokens.iter().any(|t| matches!(t, Token::Call(name) if name == "square"));
+}
+
+#[test]
+fn inlines_only_what_keeps_its_locals_and_labels() {
+    let call = "defun main 0 NULL pushi 3 call square pop endef";
+    assert!(! calls_square(call, |_| ()));
+
+    // the locals of square would come after local 65535
+    let full = "defun main 0 NULL pushi 1 store 65535 pushi 3 call square pop endef";
+    assert!(calls_square(full, |_| ()));
+    let mut vm = Vm::new();
+    vm.set_options(Options::new(2));
+    assert!(vm.load_text(&format!("{} {}", SQUARE, full)).is_ok());
+
+    // the end of square would need a label after the largest one
+    let labelled = "defun main 0 NULL pushi 3 call square pop label 1 endef";
+    for last in [u32::MAX as usize, usize::MAX] {
+        assert!(calls_square(labelled, |exec| {
+            exec.tokens[3] = Token::Label(last);
+            exec.labels = HashMap::from([(last, 3)]);
+        }));
+    }
+}
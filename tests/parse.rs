@@ -0,0 +1,102 @@
+/*!
+ * Reads malformed programs in the raw encoding and checks that every
+ * problem is reported with its position, reading on after each one.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs;
+
+/**
+ * The errors `rvmi` reports for `text`, as `line:column: message`. The
+ * test `name` writes the program to a file of that name.
+ */
+fn errors(name : &str, text : &str) -> Vec<String> {
+    let path = common::scratch("parse").join(name).with_extension("ri");
+    fs::write(&path, text).unwrap();
+    let found = common::rvmi(&[&path], b"");
+    assert_eq!(found.status.code(), Some(1));
+    let prefix = format!("{}:", path.display());
+    return String::from_utf8_lossy(&found.stderr).lines()
+        .map(|line| line.strip_prefix(&prefix).unwrap_or(line).to_string())
+        .collect();
+}
+
+#[test]
+fn reports_every_error_with_its_position() {
+    let text = "\
+.raw
+.class
+.function
+defun main 0 NULL
+pushi 1x
+foo 3
+pushi 2
+pop
+  load
+endef
+defun f 0 NULL
+stores 0 3 \"abc
+";
+    assert_eq!(errors("every_error", text), [
+        "5:7: expected an integer after `pushi`, found `1x`",
+        "6:1: expected an instruction, found `foo`",
+        "6:5: expected an instruction, found `3`",
+        "10:1: expected an address after `load`, found `endef`",
+        "13:1: expected right quotation mark, found end of file",
+        "13:1: expected `endef`, found end of file",
+    ]);
+}
+
+#[test]
+fn reports_a_missing_endef() {
+    assert_eq!(errors("missing_endef", ".raw\n.class\n.function\ndefun main 0 NULL\npushi 1\npop\n"), [
+        "7:1: expected `endef`, found end of file",
+    ]);
+}
+
+#[test]
+fn recovers_after_a_bad_operand() {
+    // the functions after the bad one are still read and checked
+    let text = ".raw .class .function
+defun main 0 NULL
+pushi -
+endef
+defun f 0 NULL
+pushf 1.5e
+endef
+defun g 0 NULL
+goto x
+endef
+";
+    assert_eq!(errors("bad_operand", text), [
+        "3:7: expected an integer after `pushi`, found `-`",
+        "6:7: expected a float after `pushf`, found `1.5e`",
+        "9:6: expected a label after `goto`, found `x`",
+    ]);
+}
+
+#[test]
+fn rejects_operands_out_of_range() {
+    let text = ".raw .class .function
+defun main 0 NULL
+store 65536
+load 18446744073709551615
+gstore 100000000000
+goto 4294967296
+stores 0 70000 \"a\"
+stores 0 2 \"a\"
+endef
+";
+    assert_eq!(errors("out_of_range", text), [
+        "3:7: expected an address after `store`, found `65536`",
+        "4:6: expected an address after `load`, found `18446744073709551615`",
+        "5:8: expected an address after `gstore`, found `100000000000`",
+        "6:6: expected a label after `goto`, found `4294967296`",
+        "7:10: expected a string length after `stores`, found `70000`",
+        "7:16: expected an instruction, found `a`",
+        "8:12: expected a string of 2 bytes after `stores`, found `a`",
+    ]);
+}
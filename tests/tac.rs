@@ -0,0 +1,33 @@
+/*!
+ * Prints every program in `tests/tac/` as three-address code with
+ * `rvmi tac` and compares it with the `.tac` file of the same name.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("tac");
+    let mut found : Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ri"))
+        .collect();
+    found.sort();
+    return found;
+}
+
+#[test]
+fn prints_the_expected_code() {
+    let programs = programs();
+    assert!(! programs.is_empty());
+    for program in &programs {
+        let output = common::rvmi(&[Path::new("tac"), program], &[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let expected = fs::read_to_string(program.with_extension("tac")).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected, "{}", program.display());
+    }
+}
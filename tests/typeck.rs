@@ -0,0 +1,138 @@
+/*!
+ * Runs programs that verify but are ill-typed and checks that the type
+ * checker reports each mismatch on its line, and only the mismatches it
+ * can be sure of.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs;
+
+use rust_vm::scanner::Token;
+use rust_vm::{parse_text, typeck, Host};
+
+/**
+ * The type errors `rvmi` reports for a program declaring `classes` and
+ * defining `functions` after an empty `main`, which takes lines 4 and 5
+ * when there are no classes. The test `name` writes the program to a
+ * file of that name.
+ */
+fn errors(name : &str, classes : &str, functions : &str) -> Vec<String> {
+    let text = format!(".raw\n.class\n{}.function\ndefun main 0 NULL\nendef\n{}", classes, functions);
+    let path = common::scratch("typeck").join(name).with_extension("ri");
+    fs::write(&path, text).unwrap();
+    let found = common::rvmi(&[&path], b"");
+    let prefix = format!("{}: ", path.display());
+    return String::from_utf8_lossy(&found.stderr).lines()
+        .map(|line| line.strip_prefix(&prefix).unwrap_or(line).to_string())
+        .collect();
+}
+
+/// a class `P` with an `int` field `x` and a method `m`
+const POINT : &str = "defcl P\nfield x int\nmethod m\nendcl\n";
+/// the method `m` of `P`, on lines 10 to 12
+const METHOD : &str = "defun P.m 1 P NULL\npop\nendef\n";
+
+#[test]
+fn rejects_mixing_int_and_float() {
+    assert_eq!(errors("mix", "", "defun f 0 NULL\npushi 1\npushf 2.0\nadd\npop\nendef\n"), [
+        "line 9: in f: `add` mixes int and float",
+    ]);
+}
+
+#[test]
+fn rejects_a_branch_on_a_float() {
+    assert_eq!(errors("branch", "", "defun f 0 NULL\npushf 1.0\nbranch 1\nlabel 1\nendef\n"), [
+        "line 8: in f: `branch` on float, expected int",
+    ]);
+}
+
+#[test]
+fn rejects_a_remainder_of_floats() {
+    assert_eq!(errors("rem", "", "defun f 0 NULL\npushf 1.0\npushf 2.0\nrem\npop\nendef\n"), [
+        "line 9: in f: `rem` on float",
+    ]);
+}
+
+#[test]
+fn rejects_arithmetic_on_strings() {
+    assert_eq!(errors("string", "", "defun f 0 NULL\nstores 0 1 \"a\"\nload 0\nload 0\nsub\npop\nendef\n"), [
+        "line 10: in f: `sub` on string",
+    ]);
+}
+
+#[test]
+fn rejects_an_argument_of_the_wrong_type() {
+    let functions = "defun g 1 int NULL\npop\nendef\ndefun f 0 NULL\npushf 1.0\ncall g\nendef\n";
+    assert_eq!(errors("argument", "", functions), [
+        "line 11: in f: argument 0 of g() is float, expected int",
+    ]);
+}
+
+#[test]
+fn rejects_returning_the_wrong_type() {
+    assert_eq!(errors("return", "", "defun f 0 int\npushf 1.0\nendef\n"), [
+        "line 7: in f: returns float, expected int",
+    ]);
+}
+
+#[test]
+fn rejects_setting_a_field_to_the_wrong_type() {
+    let functions = format!("{}defun f 0 NULL\nnew P\npushf 1.0\nsetfield P x\nendef\n", METHOD);
+    assert_eq!(errors("field", POINT, &functions), [
+        "line 16: in f: field x of P set to float, expected int",
+    ]);
+}
+
+#[test]
+fn rejects_setting_a_field_of_an_int() {
+    let functions = format!("{}defun f 0 NULL\npushi 1\npushi 2\nsetfield P x\nendef\n", METHOD);
+    assert_eq!(errors("field_of_int", POINT, &functions), [
+        "line 16: in f: `setfield` on int, expected P",
+    ]);
+}
+
+#[test]
+fn rejects_storing_an_element_of_the_wrong_type() {
+    let functions = "defun f 0 NULL\npushi 2\nnewarr int\npushi 0\npushf 1.0\nastore\nendef\n";
+    assert_eq!(errors("element", "", functions), [
+        "line 11: in f: element of int[] set to float",
+    ]);
+}
+
+#[test]
+fn rejects_storing_into_something_else() {
+    let functions = "defun f 0 NULL\npushi 2\npushi 0\npushi 1\nastore\nendef\n";
+    assert_eq!(errors("element_of_int", "", functions), [
+        "line 10: in f: `astore` on int, expected an array",
+    ]);
+}
+
+#[test]
+fn rejects_calling_a_method_of_something_else() {
+    let functions = format!("{}defun f 0 NULL\npushi 1\ncallm m\nendef\n", METHOD);
+    assert_eq!(errors("method_of_int", POINT, &functions), [
+        "line 15: in f: `callm m` on int, expected an object",
+    ]);
+}
+
+#[test]
+fn ignores_values_whose_type_depends_on_the_path() {
+    // local 1 is an int on one path and a float on the other
+    let functions = "defun f 1 int NULL\nstore 0\nload 0\nbranch 1\npushi 1\nstore 1\ngoto 2\n\
+                     label 1\npushf 1.0\nstore 1\nlabel 2\nload 1\npushi 1\nadd\npop\nendef\n";
+    assert_eq!(errors("path", "", functions), Vec::<String>::new());
+}
+
+#[test]
+fn keeps_only_the_locals_it_knows() {
+    // the verifier rejects such an index, but the checker must not allocate for it
+    let mut program = parse_text(".raw .class .function defun main 0 NULL pushi 1 store 0 load 0 pop endef").unwrap();
+    for i in [1 << 16, 100000000000, usize::MAX] {
+        program.func.defs[0].exec.tokens[1] = Token::Store(i);
+        program.func.defs[0].exec.tokens[2] = Token::Load(i);
+        assert_eq!(typeck::check(&program, &Host::new()), Ok(()));
+    }
+}
@@ -0,0 +1,169 @@
+/*!
+ * Runs programs that parse but are malformed and checks that the
+ * verifier rejects each one with the reason and the line at fault.
+ */
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs;
+
+use rust_vm::scanner::Token;
+use rust_vm::{parse_text, verify, vm, Fault, Host};
+
+/**
+ * The errors `rvmi` reports for `text`, without the file name. The test
+ * `name` writes the program to a file of that name.
+ */
+fn errors(name : &str, text : &str) -> Vec<String> {
+    let path = common::scratch("verify").join(name).with_extension("ri");
+    fs::write(&path, text).unwrap();
+    let found = common::rvmi(&[&path], b"");
+    assert_eq!(found.status.code(), Some(1), "{}", name);
+    let prefix = format!("{}: ", path.display());
+    return String::from_utf8_lossy(&found.stderr).lines()
+        .map(|line| line.strip_prefix(&prefix).unwrap_or(line).to_string())
+        .collect();
+}
+
+/**
+ * Checks that `functions`, after an empty `main`, are rejected with only
+ * `expected`.
+ */
+fn rejects(name : &str, functions : &str, expected : &str) {
+    let text = format!(".raw\n.class\n.function\ndefun main 0 NULL\nendef\n{}", functions);
+    assert_eq!(errors(name, &text), [expected]);
+}
+
+#[test]
+fn rejects_a_label_defined_twice() {
+    rejects("label_twice", "defun f 0 NULL\nlabel 1\nlabel 1\nendef\n", "line 8: in f: label 1 is defined twice");
+}
+
+#[test]
+fn rejects_a_missing_label() {
+    rejects("missing_label", "defun f 0 NULL\ngoto 2\nendef\n", "line 7: in f: label 2 not found");
+}
+
+#[test]
+fn rejects_an_unknown_function() {
+    rejects("unknown_function", "defun f 0 NULL\ncall g\nendef\n", "line 7: in f: g() not found");
+}
+
+#[test]
+fn rejects_an_unknown_class() {
+    rejects("unknown_class", "defun f 0 NULL\nnew Point\npop\nendef\n", "line 7: in f: class Point not found");
+}
+
+#[test]
+fn rejects_an_instruction_that_cannot_run() {
+    rejects("pushv", "defun f 0 NULL\npushv 0\nendef\n", "line 7: in f: `pushv 0` cannot be executed");
+}
+
+#[test]
+fn rejects_a_stack_underflow() {
+    rejects("underflow", "defun f 0 NULL\npushi 1\nadd\npop\nendef\n", "line 8: in f: `add` pops 2 values from a stack of 1");
+}
+
+#[test]
+fn rejects_paths_that_join_at_different_depths() {
+    rejects(
+        "join", "defun f 0 NULL\nlabel 1\npushi 1\ngoto 1\nendef\n",
+        "line 7: in f: stack depth 1 here disagrees with depth 0 from another path",
+    );
+}
+
+#[test]
+fn rejects_a_ret_with_the_wrong_number_of_values() {
+    rejects("ret", "defun f 0 int\nret\nendef\n", "line 7: in f: returns 0 values, expected 1");
+}
+
+#[test]
+fn rejects_an_endef_with_the_wrong_number_of_values() {
+    rejects("endef", "defun f 0 NULL\npushi 1\nendef\n", "line 7: in f: reaches endef with 1 values, expected 0");
+}
+
+#[test]
+fn rejects_a_function_defined_twice() {
+    rejects("function_twice", "defun f 0 NULL\nendef\ndefun f 0 NULL\nendef\n", "in f: function is defined twice");
+}
+
+#[test]
+fn rejects_a_function_named_like_a_host_function() {
+    rejects("host", "defun print 1 int NULL\npop\nendef\n", "in print: function has the name of a host function");
+}
+
+#[test]
+fn rejects_classes_that_inherit_from_each_other() {
+    let text = ".raw\n.class\ndefcl A\nextends B\nendcl\ndefcl B\nextends A\nendcl\n\
+                .function\ndefun main 0 NULL\nendef\n";
+    assert_eq!(errors("cycle", text), [
+        "in A: class inherits from itself",
+        "in B: class inherits from itself",
+    ]);
+}
+
+#[test]
+fn rejects_an_override_with_another_signature() {
+    let text = ".raw\n.class\ndefcl A\nmethod m\nendcl\ndefcl B\nextends A\nmethod m\nendcl\n\
+                .function\ndefun main 0 NULL\nendef\n\
+                defun A.m 1 A int\npop\npushi 1\nendef\n\
+                defun B.m 1 B NULL\npop\nendef\n";
+    assert_eq!(errors("override", text), [
+        "in B: B.m does not have the signature of A.m",
+        "in B.m: method m takes or returns other values than A.m",
+    ]);
+}
+
+#[test]
+fn rejects_a_main_with_parameters() {
+    let text = ".raw\n.class\n.function\ndefun main 1 int NULL\npop\nendef\n";
+    assert_eq!(errors("main_parameters", text), ["in main: main must not take parameters"]);
+}
+
+#[test]
+fn rejects_a_program_without_main() {
+    let text = ".raw\n.class\n.function\ndefun f 0 NULL\nendef\n";
+    assert_eq!(errors("no_main", text), ["in main: function not found"]);
+}
+
+#[test]
+fn rejects_locals_beyond_a_u16_address() {
+    // the parser cannot read such an address, but a program built otherwise has it
+    for i in [1 << 16, 100000000000, usize::MAX] {
+        let mut program = parse_text(".raw .class .function defun main 0 NULL pushi 1 store 0 endef").unwrap();
+        program.func.defs[0].exec.tokens[1] = Token::Store(i);
+        let errors = verify::verify(&program, &Host::new()).unwrap_err();
+        let messages : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, [format!("line 1: in main: local {} is beyond the 65536 locals of a function", i)]);
+    }
+}
+
+#[test]
+fn rejects_globals_beyond_a_u16_address() {
+    for i in [1 << 16, 100000000000, usize::MAX] {
+        let mut program = parse_text(".raw .class .function defun main 0 NULL pushi 1 gstore 0 endef").unwrap();
+        program.func.defs[0].exec.tokens[1] = Token::Gstore(i);
+        let errors = verify::verify(&program, &Host::new()).unwrap_err();
+        let messages : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, [format!("line 1: in main: global {} is beyond the 65536 globals of a program", i)]);
+    }
+}
+
+#[test]
+fn links_only_slots_an_address_names() {
+    // linking does not rely on the verifier to bound the slots it allocates
+    let text = ".raw .class .function defun main 0 NULL pushi 1 store 0 pushi 1 gstore 0 endef";
+    for (at, token) in [(1, Token::Store(usize::MAX)), (1, Token::Store(1 << 16)), (3, Token::Gstore(1 << 16))] {
+        let mut program = parse_text(text).unwrap();
+        program.func.defs[0].exec.tokens[at] = token;
+        match vm::Image::link(&program, &Host::new()) {
+            Err(e) => assert!(matches!(e.fault, Fault::AddressOutOfRange(_)), "{}", e),
+            Ok(_) => panic!("linked {:?}", program.func.defs[0].exec.tokens[at]),
+        }
+    }
+    let mut program = parse_text(text).unwrap();
+    program.func.defs[0].exec.tokens[1] = Token::Store(65535);
+    assert!(vm::Image::link(&program, &Host::new()).is_ok());
+}